@@ -0,0 +1,34 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const CHALLENGE_SIZE: usize = 32;
+
+///
+/// Challenge-response authentication with a shared secret. The server
+/// sends a random challenge and the client proves it knows the password
+/// by answering with an HMAC of the challenge, so the password itself
+/// never travels over the network.
+/// 
+pub fn new_challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; CHALLENGE_SIZE];
+    rand::rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+pub fn challenge_response(password: &str, challenge: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(password.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(challenge);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub fn verify_response(password: &str, challenge: &[u8], response: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(password.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(challenge);
+    // Constant time comparison
+    mac.verify_slice(response).is_ok()
+}
@@ -0,0 +1,225 @@
+use std::time::{Duration, Instant};
+use anyhow::anyhow;
+
+use crate::session::AggregateStats;
+
+/// How often the session is measured
+pub const CONTROL_INTERVAL: Duration = Duration::from_secs(2);
+/// Data waiting in the socket send queues that counts as congestion,
+/// as the time the current bitrate takes to produce it
+const CONGESTED_QUEUE_DELAY: Duration = Duration::from_millis(500);
+/// A write taking this long means some link can't keep up
+const CONGESTED_WRITE_LATENCY: Duration = Duration::from_millis(150);
+/// Intervals without congestion before the bitrate goes up again
+const STABLE_INTERVALS: u32 = 5;
+/// Changing the bitrate restarts the encoder, so small changes aren't worth it
+const MIN_CHANGE_RATIO: f64 = 0.15;
+/// Minimum time between two encoder restarts when lowering the bitrate
+const MIN_DECREASE_INTERVAL: Duration = Duration::from_secs(5);
+/// Minimum time between two encoder restarts when raising the bitrate
+const MIN_INCREASE_INTERVAL: Duration = Duration::from_secs(30);
+
+///
+/// Adjusts the encoder's target bitrate (in kbit/s) to what the bonded
+/// connections can carry. On congestion the bitrate is cut to what
+/// actually got sent, and after a while without congestion it's raised
+/// in small steps, always staying between `min_rate` and `max_rate`.
+/// 
+pub struct BitrateController {
+    min_rate: usize,
+    max_rate: usize,
+    /// Bitrate the encoder is currently running with
+    applied_rate: usize,
+    target_rate: usize,
+    last_bytes_sent: u64,
+    last_measurement: Instant,
+    last_change: Instant,
+    stable_intervals: u32
+}
+
+impl BitrateController {
+    pub fn new(min_rate: usize, max_rate: usize) -> anyhow::Result<Self> {
+        // The rate changes are relative to the current rate, which can't be 0
+        if min_rate == 0 {
+            return Err(anyhow!("min_rate has to be at least 1 kbit/s"));
+        }
+        if min_rate > max_rate {
+            return Err(anyhow!("min_rate ({min_rate}) can't be above max_rate ({max_rate})"));
+        }
+        let initial_rate = (max_rate / 2).max(min_rate);
+        Ok(Self {
+            min_rate,
+            max_rate,
+            applied_rate: initial_rate,
+            target_rate: initial_rate,
+            last_bytes_sent: 0,
+            last_measurement: Instant::now(),
+            last_change: Instant::now(),
+            stable_intervals: 0
+        })
+    }
+
+    /// Bitrate the encoder should be started with
+    pub fn bitrate(&self) -> usize {
+        self.applied_rate
+    }
+
+    ///
+    /// Feeds a new measurement of the session. Returns the new bitrate
+    /// if it differs enough from the current one to restart the encoder.
+    /// 
+    pub fn update(&mut self, stats: AggregateStats) -> Option<usize> {
+        let elapsed = self.last_measurement.elapsed().as_secs_f64();
+        // Connections that got dropped take their byte counters with them
+        let sent_bytes = stats.bytes_sent.saturating_sub(self.last_bytes_sent);
+        let sent_rate = (sent_bytes as f64 * 8.0 / 1000.0 / elapsed.max(0.001)) as usize;
+        self.last_bytes_sent = stats.bytes_sent;
+        self.last_measurement = Instant::now();
+
+        // kbit/s times seconds gives kbit, a kbit is 125 bytes
+        let congested_queue = (self.applied_rate as f64 * CONGESTED_QUEUE_DELAY.as_secs_f64() * 125.0) as usize;
+        let congested = stats.connections == 0
+            || stats.queued_bytes >= congested_queue
+            || stats.max_write_latency >= CONGESTED_WRITE_LATENCY;
+        if congested {
+            self.stable_intervals = 0;
+            // Leave some headroom under what the links managed to carry
+            let reduced_rate = (self.target_rate * 3 / 4).min(sent_rate * 9 / 10);
+            self.target_rate = reduced_rate.max(self.min_rate);
+        } else {
+            self.stable_intervals += 1;
+            if self.stable_intervals >= STABLE_INTERVALS {
+                self.stable_intervals = 0;
+                self.target_rate = (self.target_rate + self.max_rate / 10).min(self.max_rate);
+            }
+        }
+
+        let change = self.target_rate.abs_diff(self.applied_rate) as f64 / self.applied_rate as f64;
+        let min_interval = if self.target_rate < self.applied_rate {
+            MIN_DECREASE_INTERVAL
+        } else {
+            MIN_INCREASE_INTERVAL
+        };
+        if change < MIN_CHANGE_RATIO || self.last_change.elapsed() < min_interval {
+            return None;
+        }
+
+        self.applied_rate = self.target_rate;
+        self.last_change = Instant::now();
+        Some(self.applied_rate)
+    }
+
+    /// Restarts the stabilization period, e.g. after the encoder was restarted
+    pub fn reset_measurement(&mut self, stats: AggregateStats) {
+        self.last_bytes_sent = stats.bytes_sent;
+        self.last_measurement = Instant::now();
+        self.stable_intervals = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ago(duration: Duration) -> Instant {
+        Instant::now() - duration
+    }
+
+    /// A controller whose last encoder restart was long enough ago to allow any change
+    fn controller(min_rate: usize, max_rate: usize) -> BitrateController {
+        let mut controller = BitrateController::new(min_rate, max_rate).unwrap();
+        controller.last_change = ago(MIN_INCREASE_INTERVAL * 2);
+        controller
+    }
+
+    fn stable() -> AggregateStats {
+        AggregateStats {
+            connections: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Congested stats after sending `bytes_sent` since the last measurement
+    fn congested(bytes_sent: u64) -> AggregateStats {
+        AggregateStats {
+            connections: 1,
+            bytes_sent,
+            queued_bytes: usize::MAX,
+            max_write_latency: Duration::ZERO
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_rates() {
+        assert!(BitrateController::new(0, 4000).is_err());
+        assert!(BitrateController::new(5000, 4000).is_err());
+        assert_eq!(BitrateController::new(500, 4000).unwrap().bitrate(), 2000);
+        assert_eq!(BitrateController::new(3000, 4000).unwrap().bitrate(), 3000);
+    }
+
+    #[test]
+    fn congestion_cuts_below_the_measured_rate() {
+        let mut controller = controller(500, 4000);
+        // 250 kB in 2 s is 1000 kbit/s
+        controller.last_measurement = ago(Duration::from_secs(2));
+        let rate = controller.update(congested(250_000)).unwrap();
+        assert!((899..=900).contains(&rate), "{rate}");
+        assert_eq!(controller.bitrate(), rate);
+    }
+
+    #[test]
+    fn congestion_never_goes_below_the_minimum() {
+        let mut nothing_sent = controller(500, 4000);
+        assert_eq!(nothing_sent.update(congested(0)), Some(500));
+
+        // Losing every connection counts as congestion
+        let mut disconnected = controller(500, 4000);
+        let no_connections = AggregateStats::default();
+        assert_eq!(disconnected.update(no_connections), Some(500));
+        disconnected.last_change = ago(MIN_INCREASE_INTERVAL * 2);
+        assert_eq!(disconnected.update(no_connections), None);
+    }
+
+    #[test]
+    fn steps_up_after_stable_intervals() {
+        let mut controller = controller(500, 4000);
+        for _ in 1..STABLE_INTERVALS {
+            assert_eq!(controller.update(stable()), None);
+        }
+        assert_eq!(controller.update(stable()), Some(2400));
+    }
+
+    #[test]
+    fn ignores_small_changes() {
+        // 3000 to 3400 kbit/s is less than MIN_CHANGE_RATIO
+        let mut controller = controller(3000, 4000);
+        for _ in 0..STABLE_INTERVALS {
+            assert_eq!(controller.update(stable()), None);
+        }
+        assert_eq!(controller.bitrate(), 3000);
+    }
+
+    #[test]
+    fn waits_between_decreases() {
+        let mut controller = controller(500, 4000);
+        controller.last_change = Instant::now();
+        assert_eq!(controller.update(congested(0)), None);
+        assert_eq!(controller.bitrate(), 2000);
+
+        controller.last_change = ago(MIN_DECREASE_INTERVAL + Duration::from_secs(1));
+        assert_eq!(controller.update(congested(0)), Some(500));
+    }
+
+    #[test]
+    fn waits_longer_between_increases() {
+        let mut controller = controller(500, 4000);
+        controller.last_change = ago(MIN_DECREASE_INTERVAL + Duration::from_secs(1));
+        for _ in 0..STABLE_INTERVALS {
+            assert_eq!(controller.update(stable()), None);
+        }
+        assert_eq!(controller.bitrate(), 2000);
+
+        controller.last_change = ago(MIN_INCREASE_INTERVAL + Duration::from_secs(1));
+        assert_eq!(controller.update(stable()), Some(2400));
+    }
+}
@@ -1,45 +1,316 @@
-use crate::{connection::{Connection, ConnectionPacket, PacketType}, session::Session, ALLVU_VERSION};
+use crate::{auth::challenge_response, connection::{Connection, ConnectionPacket, PacketType, RejectReason}, session::{AggregateStats, HeartbeatConfig, SchedulingStrategy, Session}, ALLVU_VERSION};
+use std::{fmt, net::SocketAddr, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, RwLock}, time::Duration};
 use anyhow::anyhow;
-use tokio::spawn;
+use tokio::{net::TcpSocket, task::JoinSet, time::timeout};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+
+#[derive(Clone)]
+pub struct TlsClient {
+    pub connector: TlsConnector,
+    /// Name the server's certificate has to be issued for
+    pub server_name: ServerName<'static>
+}
+
+/// How long connecting a single interface to the server may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the handshake with the server may take
+const INTRODUCTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+///
+/// The server no longer knows the session token, e.g. because it was
+/// restarted or the session expired while every link was down
+/// 
+#[derive(Debug)]
+pub struct SessionExpired(pub String);
+
+impl fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server rejected the session: {}", self.0)
+    }
+}
+
+impl std::error::Error for SessionExpired {}
+
+///
+/// The server refused the connection for a reason retrying won't fix,
+/// e.g. a wrong password or a TLS setup that doesn't match the server's
+/// 
+#[derive(Debug)]
+pub struct ConnectionRefused(pub String);
+
+impl fmt::Display for ConnectionRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server refused the connection: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectionRefused {}
 
 pub struct ClientSession {
-    session: Session
+    session: Session,
+    server_address: SocketAddr,
+    password: String,
+    tls: Option<TlsClient>,
+    /// Token the server assigned to this session, used to attach more connections
+    server_token: RwLock<Option<String>>,
+    video_sequence: AtomicU64,
+    /// Set once the server session was replaced by a new one, video
+    /// isn't sent until the encoder starts over
+    session_restarted: AtomicBool,
+    /// The next video chunk is the first one of a new encoder run
+    stream_start: AtomicBool,
+    /// Why the server refused a connection for good, nothing gets through after that
+    refusal: RwLock<Option<String>>
 }
 
 impl ClientSession {
-    pub fn new() -> Self {
-        let return_val = Self {
-            session: Session::new()
-        };
+    pub fn new(server_address: SocketAddr, password: String) -> Self {
+        Self {
+            session: Session::new(),
+            server_address,
+            password,
+            tls: None,
+            server_token: RwLock::new(None),
+            video_sequence: AtomicU64::new(0),
+            session_restarted: AtomicBool::new(false),
+            stream_start: AtomicBool::new(false),
+            refusal: RwLock::new(None)
+        }
+    }
+
+    pub fn set_tls(&mut self, tls: TlsClient) {
+        self.tls = Some(tls);
+    }
+
+    pub fn set_strategy(&mut self, strategy: SchedulingStrategy) {
+        self.session.set_strategy(strategy)
+    }
 
-        return_val
+    pub fn set_heartbeat(&mut self, heartbeat: HeartbeatConfig) {
+        self.session.set_heartbeat(heartbeat)
     }
 
-    pub fn add_connection(&mut self, connection: Connection)
+    pub fn add_connection(&self, connection: Connection)
     {
         self.session.add_connection(connection)
     }
 
-    fn start_packet_processor(&self) {
-        let packet_channel_arc = self.session.packet_channel.clone();
-        spawn(async move {
-            let receiver = &mut packet_channel_arc.1.lock().await;
-            while let Some(packet) = receiver.recv().await {
-                
+    pub fn connection_count(&self) -> usize {
+        self.session.connection_count()
+    }
+
+    pub async fn aggregate_stats(&self) -> AggregateStats {
+        self.session.aggregate_stats().await
+    }
+
+    /// Closes the connections going over the given interface
+    pub fn retire_interface(&self, interface_name: &str) {
+        self.session.remove_connection(interface_name)
+    }
+
+    /// Names of the interfaces that currently have a connection in the session
+    pub fn connected_interfaces(&self) -> Vec<String> {
+        self.session.connection_labels()
+    }
+
+    fn server_token(&self) -> Option<String> {
+        self.server_token.read().unwrap().clone()
+    }
+
+    ///
+    /// Introduces the connection to the server, joining the session
+    /// that earlier connections created, and adds it to this session
+    /// 
+    pub async fn join(&self, connection: Connection) -> anyhow::Result<()> {
+        let token = self.server_token();
+        let introduction = introduce_connection(
+            connection,
+            &self.password,
+            token.as_deref(),
+            self.tls.as_ref()
+        );
+        let introduction_result = timeout(INTRODUCTION_TIMEOUT, introduction).await
+            .map_err(|_| anyhow!("Server didn't finish the handshake in time"))?;
+        let (connection, new_token) = match introduction_result {
+            Ok(introduction) => introduction,
+            Err(e) => {
+                if e.is::<SessionExpired>() {
+                    self.forget_session(token.as_deref());
+                } else if let Some(ConnectionRefused(reason)) = e.downcast_ref() {
+                    *self.refusal.write().unwrap() = Some(reason.clone());
+                }
+                return Err(e);
             }
-        });
+        };
+        *self.server_token.write().unwrap() = Some(new_token);
+        self.add_connection(connection);
+        Ok(())
+    }
+
+    ///
+    /// Drops the token the server rejected, so the next connection
+    /// creates a new session. Everything sent so far belonged to the
+    /// old session, so the encoder has to start over.
+    /// 
+    fn forget_session(&self, rejected_token: Option<&str>) {
+        let mut server_token = self.server_token.write().unwrap();
+        // Another connection may have already replaced it
+        if server_token.as_deref() == rejected_token {
+            *server_token = None;
+            self.session_restarted.store(true, Ordering::Release);
+        }
     }
 
-    pub async fn send(&self, packet: ConnectionPacket) -> anyhow::Result<()> {
-        self.session.send(packet).await
+    /// Set once the server refused a connection for a reason retrying won't fix
+    pub fn refusal(&self) -> Option<ConnectionRefused> {
+        self.refusal.read().unwrap().clone().map(ConnectionRefused)
     }
+
+    ///
+    /// Returns true once after the server session was replaced. The
+    /// video sequence starts over, so the caller has to restart the
+    /// encoder to give the new session a stream from its beginning.
+    /// 
+    pub fn take_session_restart(&self) -> bool {
+        let restarted = self.session_restarted.swap(false, Ordering::AcqRel);
+        if restarted {
+            self.video_sequence.store(0, Ordering::Release);
+        }
+        restarted
+    }
+
+    ///
+    /// Connects every given interface to the server at the same time
+    /// and adds the ones that succeed to the session. If the session
+    /// doesn't exist on the server yet, the first interface to connect
+    /// creates it and the rest join it. Interfaces rejected because the
+    /// server forgot the session are tried once more with a new one.
+    /// Returns how many were added, or an error once the server
+    /// refused a connection for good.
+    /// 
+    pub async fn connect_interfaces(self: &Arc<Self>, interfaces: Vec<String>) -> anyhow::Result<usize> {
+        let (mut connected, expired) = self.connect_round(interfaces).await;
+        if !expired.is_empty() {
+            println!("Server session expired, creating a new one");
+            connected += self.connect_round(expired).await.0;
+        }
+        if let Some(refusal) = self.refusal() {
+            return Err(refusal.into());
+        }
+
+        Ok(connected)
+    }
+
+    /// Returns how many interfaces were added and which ones had their session rejected
+    async fn connect_round(self: &Arc<Self>, interfaces: Vec<String>) -> (usize, Vec<String>) {
+        let mut connecting = JoinSet::new();
+        for interface_name in interfaces {
+            let server_address = self.server_address;
+            connecting.spawn(async move {
+                let connection_result = open_connection(&interface_name, server_address).await;
+                (interface_name, connection_result)
+            });
+        }
+
+        let mut joining = JoinSet::new();
+        let mut connected = 0;
+        let mut expired = vec![];
+        while let Some(join_result) = connecting.join_next().await {
+            let Ok((interface_name, connection_result)) = join_result else {
+                continue;
+            };
+            let connection = match connection_result {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("Couldn't connect to server from {interface_name}: {e}");
+                    continue;
+                }
+            };
+            println!("Connection created - {interface_name}");
+
+            if self.server_token().is_none() {
+                // Everything else has to wait for this one to create the session
+                match self.join(connection).await {
+                    Ok(()) => connected += 1,
+                    Err(e) if e.is::<SessionExpired>() => expired.push(interface_name),
+                    Err(e) => eprintln!("Couldn't create session from {interface_name}: {e}")
+                }
+            } else {
+                let client_session = self.clone();
+                joining.spawn(async move {
+                    (interface_name, client_session.join(connection).await)
+                });
+            }
+        }
+
+        while let Some(join_result) = joining.join_next().await {
+            let Ok((interface_name, result)) = join_result else {
+                continue;
+            };
+            match result {
+                Ok(()) => connected += 1,
+                Err(e) if e.is::<SessionExpired>() => expired.push(interface_name),
+                Err(e) => eprintln!("Couldn't join session from {interface_name}: {e}")
+            }
+        }
+
+        (connected, expired)
+    }
+
+    pub async fn send_video(&self, data: &[u8]) -> anyhow::Result<()> {
+        if self.session_restarted.load(Ordering::Acquire) {
+            return Err(anyhow!("The server session was restarted"));
+        }
+        let sequence = self.video_sequence.fetch_add(1, Ordering::AcqRel);
+        let stream_start = self.stream_start.swap(false, Ordering::AcqRel);
+        let packet = ConnectionPacket::video_stream(sequence, stream_start, data);
+        let send_result = self.session.send(packet).await;
+        if send_result.is_err() && stream_start {
+            // The server still has to learn where the new stream begins
+            self.stream_start.store(true, Ordering::Release);
+        }
+        send_result
+    }
+
+    ///
+    /// Marks the next video chunk as the start of a new stream, so the
+    /// server doesn't take the new FLV header for part of the old stream
+    /// 
+    pub fn start_stream(&self) {
+        self.stream_start.store(true, Ordering::Release);
+    }
+}
+
+/// Opens a TCP connection to the server that only goes through the given interface
+async fn open_connection(interface_name: &str, server_address: SocketAddr) -> anyhow::Result<Connection> {
+    let tcp_socket = TcpSocket::new_v4()?;
+    tcp_socket.bind_device(Some(interface_name.as_bytes()))?;
+    let tcp_stream = timeout(CONNECT_TIMEOUT, tcp_socket.connect(server_address)).await
+        .map_err(|_| anyhow!("Timed out connecting to {server_address}"))??;
+
+    let mut connection = Connection::new(tcp_stream);
+    connection.label = String::from(interface_name);
+    Ok(connection)
 }
 
-pub async fn introduce_connection(connection: &mut Connection) -> anyhow::Result<()> {
+///
+/// Performs the handshake with the server and attaches the connection
+/// to a session. Without a token the server creates a new session,
+/// otherwise the connection joins the session the token belongs to.
+/// When TLS is configured the rest of the handshake runs encrypted.
+/// Returns the (possibly upgraded) connection and the session token.
+/// 
+pub async fn introduce_connection(
+    mut connection: Connection,
+    password: &str,
+    token: Option<&str>,
+    tls: Option<&TlsClient>
+) -> anyhow::Result<(Connection, String)> {
     let client_greet = format!("ALLVU-CLIENT-{ALLVU_VERSION}");
     let client_greet_bytes = client_greet.as_bytes().to_vec();
     let greet_packet = ConnectionPacket {
-        packet_type: 1,
+        packet_type: PacketType::InitialGreet,
+        flags: 0,
         packet_data: client_greet_bytes
     };
 
@@ -57,18 +328,57 @@ pub async fn introduce_connection(connection: &mut Connection) -> anyhow::Result
     }
     println!("Succesfully gotten response {server_response}");
 
-    // TODO - ENCRYPTION
+    if let Some(tls) = tls {
+        connection.write(ConnectionPacket {
+            packet_type: PacketType::StartTls,
+            flags: 0,
+            packet_data: vec![]
+        }).await?;
+        let tls_packet = connection.read().await?;
+        if tls_packet.packet_type != PacketType::StartTls {
+            // Never fall back to plaintext once encryption was asked for
+            return Err(rejection_error(&tls_packet, token));
+        }
+        connection = connection.start_tls_client(&tls.connector, tls.server_name.clone()).await?;
+        println!("Connection encrypted");
+    } else {
+        eprintln!("No TLS CA configured, the connection is not encrypted");
+    }
 
-    // Retrieve token
-    connection.write(
-        ConnectionPacket { 
-            packet_type: PacketType::NewSession as u8, 
-            packet_data: "no password yet :)".as_bytes().to_vec()
+    let session_request = match token {
+        Some(token) => ConnectionPacket {
+            packet_type: PacketType::ExistingSession,
+            flags: 0,
+            packet_data: token.as_bytes().to_vec()
+        },
+        None => ConnectionPacket { 
+            packet_type: PacketType::NewSession, 
+            flags: 0,
+            packet_data: vec![]
         }
-    ).await?;
+    };
+    connection.write(session_request).await?;
 
+    // Authenticate
+    let challenge_packet = connection.read().await?;
+    if challenge_packet.packet_type == PacketType::SessionRejected {
+        return Err(rejection_error(&challenge_packet, token));
+    }
+    if challenge_packet.packet_type != PacketType::AuthChallenge {
+        return Err(anyhow!("Expected an authentication challenge"));
+    }
+    connection.write(ConnectionPacket {
+        packet_type: PacketType::AuthResponse,
+        flags: 0,
+        packet_data: challenge_response(password, &challenge_packet.packet_data)
+    }).await?;
+
+    // Retrieve token
     let token_packet = connection.read().await?;
-    let token = token_packet.to_string()?;
+    if token_packet.packet_type == PacketType::SessionRejected {
+        return Err(rejection_error(&token_packet, token));
+    }
+    let token = String::from(token_packet.to_string()?);
 
     println!("Received token {token}");
 
@@ -76,9 +386,23 @@ pub async fn introduce_connection(connection: &mut Connection) -> anyhow::Result
     while !is_server_ready {
         println!("Is server ready?");
         let packet = connection.read().await?;
-        if packet.packet_type == PacketType::ReadyForTransmission as u8 {
+        if packet.packet_type == PacketType::ReadyForTransmission {
             is_server_ready = true;
         }
     }
-    Ok(())
-}
\ No newline at end of file
+    Ok((connection, token))
+}
+
+///
+/// Turns a rejection from the server into an error the caller can act
+/// on. Only a rejected token means the session can be replaced, auth
+/// and encryption problems won't go away by reconnecting.
+/// 
+fn rejection_error(packet: &ConnectionPacket, token: Option<&str>) -> anyhow::Error {
+    match packet.to_rejection() {
+        Ok((RejectReason::UnknownSession, message)) if token.is_some() => SessionExpired(message.into()).into(),
+        Ok((RejectReason::AuthFailed | RejectReason::Encryption, message)) => ConnectionRefused(message.into()).into(),
+        Ok((_, message)) => anyhow!("Server rejected the session: {message}"),
+        Err(e) => anyhow!("Unexpected answer from the server: {e}")
+    }
+}
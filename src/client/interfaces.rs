@@ -0,0 +1,98 @@
+use std::{collections::{HashMap, HashSet}, fs::read_dir, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use tokio::{fs::read_to_string, spawn, time::interval};
+
+use crate::clisession::ClientSession;
+
+/// How often the carrier state of the interfaces is checked
+const INTERFACE_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait before retrying an interface that couldn't connect
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+///
+/// Returns the physical network interfaces that currently have a carrier
+/// 
+pub async fn get_network_interfaces() -> anyhow::Result<Vec<String>> {
+    let mut interfaces: Vec<String> = Vec::new();
+
+    let interfaces_folder = PathBuf::from("/sys/class/net");
+    for possible_entry in read_dir(interfaces_folder)? {
+        let Ok(dir_entry) = possible_entry else {
+            continue;
+        };
+
+        let path = dir_entry.path();
+        let carrier_path = path.join(PathBuf::from("carrier"));
+        if path.join(PathBuf::from("device")).exists() && carrier_path.exists() {
+            let Ok(name) = dir_entry.file_name().into_string() else {
+                continue;
+            };
+            // Reading the carrier of an interface that is down fails
+            let Ok(carrier_info) = read_to_string(carrier_path).await else {
+                continue;
+            };
+            if carrier_info.starts_with('1') {
+                interfaces.push(name);
+            }
+        }
+    }
+
+    Ok(interfaces)
+}
+
+///
+/// Rescans the interfaces' carrier state while the session is running.
+/// Interfaces that come up (e.g. a USB modem that got plugged in) are
+/// connected and added to the session, and connections over interfaces
+/// that went down are retired right away instead of waiting for their
+/// heartbeats to time out.
+/// 
+pub fn watch_interfaces(session: Arc<ClientSession>) {
+    spawn(async move {
+        let connecting: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
+        let mut last_attempts: HashMap<String, Instant> = HashMap::new();
+        let mut scan_interval = interval(INTERFACE_SCAN_INTERVAL);
+        loop {
+            scan_interval.tick().await;
+            let interfaces = match get_network_interfaces().await {
+                Ok(interfaces) => interfaces,
+                Err(e) => {
+                    eprintln!("Couldn't scan network interfaces: {e}");
+                    continue;
+                }
+            };
+
+            let connected_interfaces = session.connected_interfaces();
+            for interface_name in &connected_interfaces {
+                if !interfaces.contains(interface_name) {
+                    println!("Interface {interface_name} went down, retiring its connection");
+                    session.retire_interface(interface_name);
+                }
+            }
+
+            last_attempts.retain(|interface_name, _| interfaces.contains(interface_name));
+            for interface_name in interfaces {
+                if connected_interfaces.contains(&interface_name)
+                    || connecting.lock().unwrap().contains(&interface_name)
+                    || last_attempts.get(&interface_name).is_some_and(|attempt| attempt.elapsed() < RECONNECT_INTERVAL) {
+                    continue;
+                }
+
+                println!("Connecting interface {interface_name}");
+                last_attempts.insert(interface_name.clone(), Instant::now());
+                connecting.lock().unwrap().insert(interface_name.clone());
+                let session = session.clone();
+                let connecting = connecting.clone();
+                // Connecting can take a while, keep scanning meanwhile
+                spawn(async move {
+                    match session.connect_interfaces(vec![interface_name.clone()]).await {
+                        Ok(0) => {}
+                        Ok(_) => println!("Added {interface_name}, {} connections in total", session.connection_count()),
+                        // The main loop stops the client
+                        Err(e) => eprintln!("Couldn't add {interface_name}: {e}")
+                    }
+                    connecting.lock().unwrap().remove(&interface_name);
+                });
+            }
+        }
+    });
+}
@@ -1,26 +1,77 @@
-use std::{fs::read_dir, net::{SocketAddr, ToSocketAddrs}, path::PathBuf};
+use std::{net::ToSocketAddrs, path::PathBuf, sync::Arc, time::{Duration, Instant}};
 use anyhow::anyhow;
-use clisession::{introduce_connection, ClientSession};
-use ffmpeg::{AudioEncoder, Output, VideoEncoder};
+use bitrate::{BitrateController, CONTROL_INTERVAL};
+use clisession::{ClientSession, TlsClient};
+use ffmpeg::{encoder_chain, log_progress, select_encoder, AudioEncoder, EncoderSettings, FFmpegCommand, Input, InputType, Output, OutputType, VideoEncoder, VideoSettings};
+use input::{get_camera, get_input_source};
+use interfaces::{get_network_interfaces, watch_interfaces};
 use serde::Deserialize;
-use tokio::{fs::read_to_string, net::TcpSocket};
-use crate::{connection::{Connection, ConnectionPacket}, ffmpeg::FFmpeg};
+use supervisor::{RestartPolicy, RestartReason, Shutdown, Supervisor, STOP_GRACE_PERIOD};
+use tokio::{fs::read_to_string, select, time::{interval, sleep, timeout}};
+use tokio_rustls::rustls::pki_types::ServerName;
+use crate::{ffmpeg::FFmpeg, session::{heartbeat_config, SchedulingStrategy}, tls::load_connector};
 
+// Shared between the binaries, each one only uses part of them
+#[path ="../auth.rs"]
+#[allow(dead_code)]
+mod auth;
 #[path ="../connection.rs"]
+#[allow(dead_code)]
 mod connection;
 #[path ="../ffmpeg.rs"]
+#[allow(dead_code)]
 mod ffmpeg;
+#[path ="../input.rs"]
+#[allow(dead_code)]
+mod input;
 #[path ="../session.rs"]
+#[allow(dead_code)]
 mod session;
+#[path ="../supervisor.rs"]
+#[allow(dead_code)]
+mod supervisor;
+#[path ="../tls.rs"]
+#[allow(dead_code)]
+mod tls;
+mod bitrate;
 mod clisession;
+mod interfaces;
 
 const ALLVU_PORT: u16 = 1312;
 const ALLVU_VERSION: &str = env!("CARGO_PKG_VERSION");
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Queued packets per input, so the camera doesn't drop frames while PulseAudio starts
+const INPUT_QUEUE_SIZE: u32 = 512;
+/// How often the connections are checked while waiting for a link to come back
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Deserialize)]
 struct Config {
     server: String,
-    camera: String
+    /// Replaced by `camera_pat`, only read to reject old configs
+    camera: Option<String>,
+    /// Part of the camera's name, the first camera is used if not set
+    camera_pat: Option<String>,
+    /// Part of the PulseAudio source's name, the default source is used if not set
+    audio_pat: Option<String>,
+    /// Shared secret configured on the server
+    password: Option<String>,
+    /// PEM file with the CA the server's certificate is issued by, enables TLS
+    tls_ca: Option<String>,
+    /// Name on the server's certificate, defaults to `server`
+    tls_server_name: Option<String>,
+    scheduling: Option<SchedulingStrategy>,
+    /// Time between heartbeats on every connection, in milliseconds
+    heartbeat_interval: Option<u64>,
+    /// Unanswered heartbeats after which a connection is dropped
+    max_missed_heartbeats: Option<u32>,
+    /// Lowest video bitrate the encoder is lowered to, in kbit/s
+    min_rate: Option<usize>,
+    /// Highest video bitrate the encoder is raised to, in kbit/s
+    max_rate: Option<usize>,
+    /// Encoders tried in order (H264, HEVC or AV1 for VAAPI, X264, X265,
+    /// SVTAV1 or VP9 for software encoding), the first one that works is used
+    encoders: Option<Vec<String>>
 }
 
 async fn get_config() -> anyhow::Result<Config> {
@@ -31,30 +82,64 @@ async fn get_config() -> anyhow::Result<Config> {
 
     let contents = read_to_string(config_path).await?;
     let config_file: Config = toml::from_str(&contents)?;
+    if config_file.camera.is_some() {
+        return Err(anyhow!("The camera option was replaced by camera_pat, which takes part of the camera's name"));
+    }
     Ok(config_file)
 }
 
-async fn get_network_interfaces() -> anyhow::Result<Vec<String>> {
-    let mut interfaces: Vec<String> = Vec::new();
+enum ClientState {
+    /// Connecting the available interfaces to the server
+    Connecting,
+    /// Looking for the camera and audio input and starting FFmpeg
+    StartingPipeline,
+    /// Sending FFmpeg's output over the session
+    Streaming(Box<FFmpeg>)
+}
 
-    let interfaces_folder = PathBuf::from("/sys/class/net");
-    for possible_entry in read_dir(interfaces_folder)? {
-        let Ok(dir_entry) = possible_entry else {
-            continue;
-        };
+///
+/// Starts the camera FFmpeg with the given video bitrate (kbit/s).
+/// `stream_offset` is how long the stream has already been running, it
+/// keeps the timestamps going forward when the encoder is restarted.
+/// 
+async fn start_pipeline(config: &Config, video_encoder: VideoEncoder, bitrate: usize, stream_offset: Duration) -> anyhow::Result<FFmpeg> {
+    let camera_path = get_camera(config.camera_pat.as_deref()).await?;
+    println!("Camera path: {camera_path}");
+    let input_name = get_input_source(config.audio_pat.as_deref()).await?;
+    println!("PulseAudio input: {input_name}");
 
-        let path = dir_entry.path();
-        let carrier_path = path.join(PathBuf::from("carrier"));
-        if path.join(PathBuf::from("device")).exists() && carrier_path.exists() {
-            let name = dir_entry.file_name().into_string().unwrap();
-            let carrier_info = read_to_string(carrier_path).await?;
-            if carrier_info.chars().nth(0) == Some('1') {
-                interfaces.push(name);
-            }
-        }
-    }
+    let command = FFmpegCommand::new()
+        .input(Input::new(camera_path, InputType::V4L2).thread_queue_size(INPUT_QUEUE_SIZE))
+        .input(Input::new(input_name, InputType::PulseAudio).thread_queue_size(INPUT_QUEUE_SIZE))
+        .video_encoder(video_encoder)
+        .video_settings(VideoSettings {
+            bitrate: Some(bitrate),
+            max_rate: Some(bitrate),
+            buffer_size: Some(bitrate * 2),
+            ..Default::default()
+        })
+        .audio_encoder(AudioEncoder::AAC)
+        .output(Output::new("-", OutputType::FLV).ts_offset(stream_offset));
 
-    Ok(interfaces)
+    let mut camera_ffmpeg = FFmpeg::new();
+    camera_ffmpeg.start(command)?;
+    log_progress(camera_ffmpeg.progress(), PROGRESS_LOG_INTERVAL);
+
+    Ok(camera_ffmpeg)
+}
+
+///
+/// Lets FFmpeg finish the stream and sends the rest of its output,
+/// so the server gets a complete FLV that ends with a whole tag
+/// 
+async fn finish_stream(camera_ffmpeg: &mut FFmpeg, session: &ClientSession) {
+    camera_ffmpeg.interrupt();
+    let _ = timeout(STOP_GRACE_PERIOD, async {
+        while let Ok(bytes) = camera_ffmpeg.read().await {
+            let _ = session.send_video(&bytes).await;
+        }
+    }).await;
+    let _ = camera_ffmpeg.stop().await;
 }
 
 #[tokio::main]
@@ -62,9 +147,8 @@ async fn main() -> anyhow::Result<()> {
     println!("Client mode");
 
     let config = get_config().await?;
-    let camera_path = config.camera;
 
-    let server_address_str = format!("{}:1312", config.server);
+    let server_address_str = format!("{}:{ALLVU_PORT}", config.server);
     let mut server_addresses = server_address_str.to_socket_addrs().expect("Couldnt resolve server address");
 
     for addr in server_addresses.clone() {
@@ -75,59 +159,169 @@ async fn main() -> anyhow::Result<()> {
         panic!("Server has no addresses");
     };
 
-    let mut session = ClientSession::new();
-    
-    println!("Checking interfaces");
-    let interfaces = get_network_interfaces().await?;
-    println!("{:?}", interfaces);
-    for interface_name in interfaces {
-        let Ok(tcp_socket) = TcpSocket::new_v4() else {
-            eprintln!("Couldn't create TcpSocket for {interface_name}");
-            continue;
-        };
-        let Ok(_) = tcp_socket.bind_device(Some(interface_name.as_bytes())) else {
-            eprintln!("Couldn't bind TcpSocket to {interface_name}");
-            continue;
-        };
-        let Ok(tcp_stream) = tcp_socket.connect(server_address).await else {
-            eprintln!("Couldn't connect to server from {interface_name}");
-            eprintln!("{server_address}");
-            continue;
-        };
-        println!("Connection created - {interface_name}");
-        let mut connection = Connection::new(tcp_stream);
-        introduce_connection(&mut connection).await?;
-        session.add_connection(connection);
+    let mut session = ClientSession::new(server_address, config.password.clone().unwrap_or_default());
+    if let Some(ca_path) = &config.tls_ca {
+        let server_name = config.tls_server_name.clone().unwrap_or_else(|| config.server.clone());
+        session.set_tls(TlsClient {
+            connector: load_connector(ca_path)?,
+            server_name: ServerName::try_from(server_name)?
+        });
     }
+    session.set_strategy(config.scheduling.unwrap_or_default());
+    session.set_heartbeat(heartbeat_config(config.heartbeat_interval, config.max_missed_heartbeats)?);
+    let session = Arc::new(session);
 
-    let mut camera_ffmpeg = FFmpeg::new();
-    camera_ffmpeg.video_encoder = VideoEncoder::VAAPIH264;
-    camera_ffmpeg.audio_encoder = AudioEncoder::AAC;
-    camera_ffmpeg.output = Some(Output {
-        path: "-".into(),
-        output_type: ffmpeg::OutputType::FLV
-    });
+    let mut bitrate_controller = BitrateController::new(
+        config.min_rate.unwrap_or(500),
+        config.max_rate.unwrap_or(4000)
+    )?;
+    let mut control_interval = interval(CONTROL_INTERVAL);
+    let mut stream_started: Option<Instant> = None;
 
-    camera_ffmpeg.start(vec![
-        "-i", &camera_path
-    ])?;
+    let mut shutdown = Shutdown::listen()?;
+    let mut supervisor = Supervisor::new("Camera FFmpeg", RestartPolicy::default());
+    let encoder_names = config.encoders.clone().unwrap_or(vec!["H264".into(), "X264".into()]);
+    let encoder_chain = encoder_chain(&encoder_names, &EncoderSettings::default())?;
+    let mut selected_encoder: Option<VideoEncoder> = None;
 
+    let mut watching_interfaces = false;
+    let mut state = ClientState::Connecting;
     loop {
-        println!("reading ffmpeg");
-        let Ok(bytes) = camera_ffmpeg.read().await else {
-            eprintln!("Error reading from FFmpeg");
-            continue;
-        };
-        println!("read {}", bytes.len());
-        println!("{:?}", bytes);
-        let packet = ConnectionPacket {
-            packet_type: 20,
-            packet_data: bytes
+        state = match state {
+            ClientState::Connecting if watching_interfaces => {
+                if let Some(refusal) = session.refusal() {
+                    return Err(refusal.into());
+                }
+                // The interface watcher keeps reconnecting, wait until one of the links is back
+                if session.connection_count() > 0 {
+                    println!("Reconnected over {} interfaces", session.connection_count());
+                    ClientState::StartingPipeline
+                } else {
+                    select! {
+                        _ = sleep(RECONNECT_POLL_INTERVAL) => {}
+                        _ = shutdown.wait() => return Ok(())
+                    }
+                    ClientState::Connecting
+                }
+            }
+            ClientState::Connecting => {
+                println!("Checking interfaces");
+                let interfaces = get_network_interfaces().await?;
+                println!("{:?}", interfaces);
+                let connected = session.connect_interfaces(interfaces).await?;
+                if connected == 0 {
+                    eprintln!("Couldn't connect to the server from any interface, retrying...");
+                    select! {
+                        _ = sleep(Duration::from_secs(3)) => {}
+                        _ = shutdown.wait() => return Ok(())
+                    }
+                    ClientState::Connecting
+                } else {
+                    println!("Connected over {connected} interfaces");
+                    watch_interfaces(session.clone());
+                    watching_interfaces = true;
+                    ClientState::StartingPipeline
+                }
+            }
+            ClientState::StartingPipeline => {
+                // The encoder starts over anyway, a replaced session needs nothing else
+                session.take_session_restart();
+                let video_encoder = match &selected_encoder {
+                    Some(video_encoder) => Ok(video_encoder.clone()),
+                    None => select_encoder(&encoder_chain, OutputType::FLV).await
+                };
+                let stream_offset = stream_started.map(|started| started.elapsed()).unwrap_or_default();
+                let pipeline_result = match video_encoder {
+                    Ok(video_encoder) => {
+                        selected_encoder = Some(video_encoder.clone());
+                        start_pipeline(&config, video_encoder, bitrate_controller.bitrate(), stream_offset).await
+                    }
+                    Err(e) => Err(e)
+                };
+                match pipeline_result {
+                    Ok(camera_ffmpeg) => {
+                        session.start_stream();
+                        supervisor.started();
+                        stream_started.get_or_insert_with(Instant::now);
+                        bitrate_controller.reset_measurement(session.aggregate_stats().await);
+                        ClientState::Streaming(Box::new(camera_ffmpeg))
+                    }
+                    Err(e) => {
+                        if selected_encoder.as_ref().is_some_and(|encoder| encoder.caused(&e.to_string())) {
+                            selected_encoder = None;
+                        }
+                        if !supervisor.wait_for_restart(RestartReason::StartFailed(e.to_string()), &mut shutdown).await {
+                            return Ok(());
+                        }
+                        ClientState::StartingPipeline
+                    }
+                }
+            }
+            ClientState::Streaming(mut camera_ffmpeg) => {
+                select! {
+                    read_result = camera_ffmpeg.read() => {
+                        match read_result {
+                            Ok(_) if session.refusal().is_some() => {
+                                // A wrong password or TLS setup won't fix itself
+                                finish_stream(&mut camera_ffmpeg, &session).await;
+                                return Err(session.refusal().unwrap().into());
+                            }
+                            Ok(_) if session.connection_count() == 0 => {
+                                eprintln!("Lost every connection to the server, stopping FFmpeg until one is back");
+                                let _ = camera_ffmpeg.stop().await;
+                                ClientState::Connecting
+                            }
+                            Ok(_) if session.take_session_restart() => {
+                                // The new server session needs a stream from its beginning
+                                println!("Server session was replaced, restarting FFmpeg");
+                                let _ = camera_ffmpeg.stop().await;
+                                ClientState::StartingPipeline
+                            }
+                            Ok(bytes) => {
+                                if let Err(e) = session.send_video(&bytes).await {
+                                    eprintln!("Error sending to server {e}");
+                                }
+                                ClientState::Streaming(camera_ffmpeg)
+                            }
+                            Err(e) => {
+                                let error = camera_ffmpeg.fatal_error().unwrap_or(e.to_string());
+                                let _ = camera_ffmpeg.stop().await;
+                                // The GPU driver may be the cause, then the encoder is probed again
+                                if selected_encoder.as_ref().is_some_and(|encoder| encoder.caused(&error)) {
+                                    selected_encoder = None;
+                                }
+                                if !supervisor.wait_for_restart(RestartReason::Failed(error), &mut shutdown).await {
+                                    return Ok(());
+                                }
+                                ClientState::StartingPipeline
+                            }
+                        }
+                    }
+                    _ = control_interval.tick() => {
+                        if let Some(error) = camera_ffmpeg.fatal_error() {
+                            let _ = camera_ffmpeg.stop().await;
+                            if selected_encoder.as_ref().is_some_and(|encoder| encoder.caused(&error)) {
+                                selected_encoder = None;
+                            }
+                            if !supervisor.wait_for_restart(RestartReason::Failed(error), &mut shutdown).await {
+                                return Ok(());
+                            }
+                            ClientState::StartingPipeline
+                        } else if let Some(bitrate) = bitrate_controller.update(session.aggregate_stats().await) {
+                            // The encoder can't change its bitrate while running
+                            println!("Restarting FFmpeg with a bitrate of {bitrate}K");
+                            finish_stream(&mut camera_ffmpeg, &session).await;
+                            ClientState::StartingPipeline
+                        } else {
+                            ClientState::Streaming(camera_ffmpeg)
+                        }
+                    }
+                    _ = shutdown.wait() => {
+                        finish_stream(&mut camera_ffmpeg, &session).await;
+                        return Ok(());
+                    }
+                }
+            }
         };
-        println!("writing conn packet");
-        if let Err(e) = session.send(packet).await {
-            eprintln!("Error sending to server {e}");
-        }
-        println!("written");
     }
-}
\ No newline at end of file
+}
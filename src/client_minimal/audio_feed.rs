@@ -0,0 +1,82 @@
+use std::time::Duration;
+use tokio::{io::AsyncWriteExt, process::ChildStdin, select, time::interval};
+
+use crate::{ffmpeg::{AudioEncoder, FFmpeg, FFmpegCommand, Input, InputType, Output, OutputType, VideoEncoder, PCM_CHANNELS, PCM_SAMPLE_RATE}, input::get_input_source};
+
+/// Length of each block of silence
+const SILENCE_PERIOD: Duration = Duration::from_millis(20);
+/// One signed 16 bit sample per channel
+const PCM_FRAME_SIZE: usize = 2 * PCM_CHANNELS as usize;
+
+/// Captures the PulseAudio source as raw audio on stdout
+fn start_capture(source: String) -> anyhow::Result<FFmpeg> {
+    let command = FFmpegCommand::new()
+        .input(Input::new(source, InputType::PulseAudio))
+        .video_encoder(VideoEncoder::Copy)
+        .audio_encoder(AudioEncoder::PCM)
+        .output(Output::new("-", OutputType::PCM));
+    let mut capture = FFmpeg::new();
+    capture.start(command)?;
+
+    Ok(capture)
+}
+
+///
+/// Feeds the encoder's raw audio input. Silence is written in real time
+/// while no PulseAudio source matches `audio_pat`, and once one shows
+/// up its capture is passed through instead. The encoder keeps running,
+/// so the stream's output connection isn't interrupted. If the source
+/// goes away again, silence takes over. Returns once the encoder stops
+/// taking audio.
+/// 
+pub async fn feed_audio(mut encoder_input: ChildStdin, audio_pat: String, poll_interval: Duration) {
+    let silence_frames = (PCM_SAMPLE_RATE as f64 * SILENCE_PERIOD.as_secs_f64()) as usize;
+    let silence = vec![0u8; silence_frames * PCM_FRAME_SIZE];
+    // Missed ticks are caught up on, so the encoder gets the right amount of audio
+    let mut silence_interval = interval(SILENCE_PERIOD);
+    let mut source_check = interval(poll_interval);
+    let mut capture: Option<FFmpeg> = None;
+    // Captured bytes that don't make up a whole frame yet
+    let mut partial_frame: Vec<u8> = Vec::new();
+
+    loop {
+        let write_result = match &mut capture {
+            Some(capture_ffmpeg) => match capture_ffmpeg.read().await {
+                Ok(bytes) => {
+                    partial_frame.extend_from_slice(&bytes);
+                    let whole_frames = partial_frame.len() - partial_frame.len() % PCM_FRAME_SIZE;
+                    let frames: Vec<u8> = partial_frame.drain(..whole_frames).collect();
+                    encoder_input.write_all(&frames).await
+                }
+                Err(e) => {
+                    eprintln!("Audio input stopped ({e}), streaming silence");
+                    let _ = capture_ffmpeg.stop().await;
+                    capture = None;
+                    partial_frame.clear();
+                    silence_interval.reset();
+                    Ok(())
+                }
+            },
+            None => select! {
+                _ = silence_interval.tick() => encoder_input.write_all(&silence).await,
+                _ = source_check.tick() => {
+                    if let Ok(source) = get_input_source(Some(&audio_pat)).await {
+                        match start_capture(source) {
+                            Ok(capture_ffmpeg) => {
+                                println!("Audio input appeared, switching to it");
+                                capture = Some(capture_ffmpeg);
+                            }
+                            Err(e) => eprintln!("Couldn't capture the audio input: {e}")
+                        }
+                    }
+                    Ok(())
+                }
+            }
+        };
+
+        // FFmpeg exited, the capture is killed when dropped
+        if write_result.is_err() {
+            return;
+        }
+    }
+}
@@ -1,20 +1,37 @@
 use std::{env, path::PathBuf, time::Duration};
 use anyhow::anyhow;
+use audio_feed::feed_audio;
 use camlink_fixer::fix_camlink;
-use ffmpeg::{AudioEncoder, FFmpeg, Input, InputType, Output, VideoEncoder};
+use ffmpeg::{encoder_chain, log_progress, select_encoder, AudioEncoder, AudioSettings, EncoderSettings, FFmpeg, FFmpegCommand, Input, InputType, Output, OutputType, VideoEncoder, VideoFormat, VideoSettings};
 use input::{get_camera, get_input_source};
 use serde::Deserialize;
-use tokio::{fs::read_to_string, time::sleep};
+use supervisor::{RestartPolicy, RestartReason, Shutdown, Supervisor, STOP_GRACE_PERIOD};
+use tokio::{fs::read_to_string, select, spawn, time::sleep};
 
+// Shared between the binaries, each one only uses part of them
 #[path ="../ffmpeg.rs"]
+#[allow(dead_code)]
 mod ffmpeg;
 
 #[path ="../camlink_fixer.rs"]
+#[allow(dead_code)]
 mod camlink_fixer;
 
 #[path ="../input.rs"]
+#[allow(dead_code)]
 mod input;
 
+#[path ="../supervisor.rs"]
+#[allow(dead_code)]
+mod supervisor;
+
+mod audio_feed;
+
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Queued packets per input, so the camera doesn't drop frames while PulseAudio starts
+const INPUT_QUEUE_SIZE: u32 = 512;
+const AUDIO_SOURCE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Deserialize)]
 struct Config {
     stream_url: String,
@@ -24,7 +41,64 @@ struct Config {
     min_rate: Option<usize>,
     max_rate: Option<usize>,
     avg_rate: Option<usize>,
-    codec: Option<String>
+    /// H264, HEVC or AV1 for VAAPI, X264, X265, SVTAV1 or VP9 for software encoding
+    codec: Option<String>,
+    /// Encoders tried in order, the first one that works is used.
+    /// Defaults to `codec` with X264 as the fallback.
+    encoders: Option<Vec<String>>,
+    /// Frames between keyframes
+    gop: Option<u32>,
+    /// Seconds between forced keyframes
+    keyframe_interval: Option<f32>,
+    /// Output resolution, the input's if neither is set
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Output framerate, the input's if not set
+    framerate: Option<u32>,
+    /// For interlaced (e.g. 1080i) cameras
+    #[serde(default)]
+    deinterlace: bool,
+    #[serde(default)]
+    audio: AudioConfig,
+    #[serde(flatten)]
+    encoder_settings: EncoderSettings,
+    /// `keyframe_interval`, checked when the config is loaded
+    #[serde(skip)]
+    keyframe_period: Option<Duration>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct AudioConfig {
+    /// AAC or OPUS, Opus only works with SRT
+    codec: Option<String>,
+    /// In kbit/s
+    bitrate: Option<usize>,
+    sample_rate: Option<u32>,
+    /// 1 downmixes the input to mono
+    channels: Option<u32>,
+    /// Target loudness in LUFS, enables loudness normalization
+    loudness: Option<f32>,
+    /// Peak ceiling in dBFS, enables the limiter
+    limiter: Option<f32>,
+    missing_source: MissingAudioPolicy
+}
+
+///
+/// What to do when there's no PulseAudio source matching `audio_pat`.
+/// With silence the stream switches to the source once it shows up,
+/// video only restarts the stream to add the audio track.
+/// 
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+enum MissingAudioPolicy {
+    /// Don't stream until the source shows up
+    #[default]
+    Wait,
+    /// Stream generated silence, for ingests that require an audio track
+    Silence,
+    /// Stream without an audio track
+    VideoOnly
 }
 
 async fn get_config() -> anyhow::Result<Config> {
@@ -37,23 +111,93 @@ async fn get_config() -> anyhow::Result<Config> {
     }
 
     let contents = read_to_string(config_path).await?;
-    let config_file: Config = toml::from_str(&contents)?;
+    let mut config_file: Config = toml::from_str(&contents)?;
+    let video_options = [
+        ("gop", config_file.gop),
+        ("width", config_file.width),
+        ("height", config_file.height),
+        ("framerate", config_file.framerate)
+    ];
+    for (option, value) in video_options {
+        if value == Some(0) {
+            return Err(anyhow!("{option} has to be greater than 0"));
+        }
+    }
+    if let Some(seconds) = config_file.keyframe_interval {
+        let Some(period) = Duration::try_from_secs_f32(seconds).ok().filter(|period| !period.is_zero()) else {
+            return Err(anyhow!("keyframe_interval has to be a positive number of seconds, got {seconds}"));
+        };
+        config_file.keyframe_period = Some(period);
+    }
     Ok(config_file)
 }
 
+/// Resolves once a PulseAudio source matching the pattern exists
+async fn wait_for_audio_source(audio_pat: &str) {
+    loop {
+        sleep(AUDIO_SOURCE_POLL_INTERVAL).await;
+        if get_input_source(Some(audio_pat)).await.is_ok() {
+            return;
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     println!("AllVu minimal client");
     let config = get_config().await?;
+    let mut shutdown = Shutdown::listen()?;
+    let mut supervisor = Supervisor::new("FFmpeg", RestartPolicy::default());
+    let encoder_names = match &config.encoders {
+        Some(encoders) => encoders.clone(),
+        None => vec![config.codec.clone().unwrap_or("H264".into()), "X264".into()]
+    };
+    let encoder_chain = encoder_chain(&encoder_names, &config.encoder_settings)?;
+    let output_type = if config.stream_url.starts_with("rtmp://") {
+        OutputType::FLV
+    } else if config.stream_url.starts_with("srt://") {
+        OutputType::MPEGTS
+    } else {
+        return Err(anyhow!("Only rtmp:// and srt:// stream URLs are supported"));
+    };
+    let mut selected_encoder: Option<VideoEncoder> = None;
+
+    let audio_encoder = match config.audio.codec.as_deref().map(str::to_uppercase).as_deref() {
+        None | Some("AAC") => AudioEncoder::AAC,
+        Some("OPUS") => AudioEncoder::Opus,
+        Some(codec) => {
+            return Err(anyhow!("Unknown audio codec {codec}"));
+        }
+    };
+    if matches!(audio_encoder, AudioEncoder::Opus) && !output_type.supports_opus() {
+        return Err(anyhow!("Opus audio can only be sent over SRT"));
+    }
+    let mut audio_filters = Vec::new();
+    if let Some(loudness) = config.audio.loudness {
+        audio_filters.push(format!("loudnorm=I={loudness}:TP=-1.5:LRA=11"));
+    }
+    if let Some(limiter) = config.audio.limiter {
+        // alimiter takes the ceiling as a linear level
+        let limit = 10f32.powf(limiter / 20.0).clamp(0.0625, 1.0);
+        audio_filters.push(format!("alimiter=limit={limit:.4}"));
+    }
+    let audio_settings = AudioSettings {
+        bitrate: config.audio.bitrate,
+        // loudnorm outputs 192 kHz, which is more than AAC can take
+        sample_rate: config.audio.sample_rate.or(config.audio.loudness.map(|_| 48000)),
+        channels: config.audio.channels
+    };
 
     loop {
-        let mut ffmpeg_stream = FFmpeg::new();
+        let mut command = FFmpegCommand::new();
+        // Set when streaming without an audio track, until the PulseAudio source shows up
+        let mut audio_missing = false;
+        // Set when the audio comes through stdin, silence until the source shows up
+        let mut feed_audio_input = false;
+        let mut has_audio = true;
 
         if let Some(debug_input) = &config.debug_input {
-            ffmpeg_stream.inputs.push(Input {
-                path: debug_input.clone(),
-                input_type: InputType::AutoDetect
-            });
+            command = command.input(Input::new(debug_input.clone(), InputType::AutoDetect));
         } else {
             // CamLink fix - we're using them for camera input
             println!("Fixing camlink...");
@@ -64,80 +208,138 @@ async fn main() -> anyhow::Result<()> {
             } 
 
             let camera_name_result = get_camera(Some(&config.camera_pat)).await;
-            let Ok(camera_name) = camera_name_result else {
-                eprintln!("Couldn't get camera name {:?}, retrying...", camera_name_result.unwrap_err());
-                sleep(Duration::from_secs(3)).await;
-                continue;
+            let camera_name = match camera_name_result {
+                Ok(camera_name) => camera_name,
+                Err(e) => {
+                    let reason = RestartReason::StartFailed(format!("couldn't get camera name: {e}"));
+                    if !supervisor.wait_for_restart(reason, &mut shutdown).await {
+                        return Ok(());
+                    }
+                    continue;
+                }
             };
             println!("Camera path: {camera_name}");
 
-            ffmpeg_stream.inputs.push(Input {
-                path: camera_name,
-                input_type: InputType::V4L2
-            });
+            command = command.input(Input::new(camera_name, InputType::V4L2).thread_queue_size(INPUT_QUEUE_SIZE));
     
-            let Ok(input_name) = get_input_source(Some(&config.audio_pat)).await else {
-                eprintln!("Couldn't get audio input name, retrying...");
-                sleep(Duration::from_secs(3)).await;
-                continue;
-            };
-    
-            println!("PulseAudio input: {input_name}");
-
-            ffmpeg_stream.inputs.push(Input {
-                path: input_name,
-                input_type: InputType::PulseAudio
-            });
-        }
-        
-        ffmpeg_stream.video_encoder = VideoEncoder::VAAPIH264;
-        if let Some(codec) = &config.codec {
-            match codec.to_uppercase().as_str() {
-                "H264" => {
-                    ffmpeg_stream.video_encoder = VideoEncoder::VAAPIH264;
-                },
-                "HEVC" => {
-                    ffmpeg_stream.video_encoder = VideoEncoder::VAAPIHEVC;
-                },
-                _ => {}
+            match get_input_source(Some(&config.audio_pat)).await {
+                Ok(input_name) => {
+                    println!("PulseAudio input: {input_name}");
+                    command = command.input(Input::new(input_name, InputType::PulseAudio).thread_queue_size(INPUT_QUEUE_SIZE));
+                }
+                Err(e) => match config.audio.missing_source {
+                    MissingAudioPolicy::Wait => {
+                        let reason = RestartReason::StartFailed(format!("couldn't get audio input name: {e}"));
+                        if !supervisor.wait_for_restart(reason, &mut shutdown).await {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                    MissingAudioPolicy::Silence => {
+                        eprintln!("Couldn't get audio input name ({e}), streaming silence");
+                        command = command.input(Input::new("pipe:0", InputType::PCM).thread_queue_size(INPUT_QUEUE_SIZE));
+                        feed_audio_input = true;
+                    }
+                    MissingAudioPolicy::VideoOnly => {
+                        eprintln!("Couldn't get audio input name ({e}), streaming without audio");
+                        has_audio = false;
+                        audio_missing = true;
+                    }
+                }
             }
         }
         
-        ffmpeg_stream.audio_encoder = AudioEncoder::AAC;
-
-        let stream_url = config.stream_url.clone();
-
-        if stream_url.starts_with("rtmp://") {
-            ffmpeg_stream.output = Some(Output {
-                path: stream_url.clone(),
-                output_type: ffmpeg::OutputType::FLV
-            });
-        } else if stream_url.starts_with("srt://") {
-            ffmpeg_stream.output = Some(Output {
-                path: stream_url.clone(),
-                output_type: ffmpeg::OutputType::MPEGTS
-            });
+        // Probed again after the encoder failed, the GPU driver may be the cause
+        let video_encoder = match &selected_encoder {
+            Some(video_encoder) => video_encoder.clone(),
+            None => match select_encoder(&encoder_chain, output_type).await {
+                Ok(video_encoder) => {
+                    selected_encoder = Some(video_encoder.clone());
+                    video_encoder
+                }
+                Err(e) => {
+                    if !supervisor.wait_for_restart(RestartReason::StartFailed(e.to_string()), &mut shutdown).await {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            }
+        };
+
+        let max_rate = config.max_rate.unwrap_or(4000);
+        command = command
+            .video_encoder(video_encoder)
+            .video_settings(VideoSettings {
+                bitrate: Some(config.avg_rate.unwrap_or(max_rate)),
+                min_rate: Some(config.min_rate.unwrap_or(500)),
+                max_rate: Some(max_rate),
+                buffer_size: Some(10000),
+                gop: config.gop,
+                keyframe_interval: config.keyframe_period
+            })
+            .video_format(VideoFormat {
+                width: config.width,
+                height: config.height,
+                framerate: config.framerate,
+                deinterlace: config.deinterlace
+            })
+            .output(Output::new(config.stream_url.clone(), output_type));
+        if has_audio {
+            command = command
+                .audio_encoder(audio_encoder.clone())
+                .audio_settings(audio_settings.clone());
+            for filter in &audio_filters {
+                command = command.audio_filter(filter.clone());
+            }
+        } else {
+            command = command.audio_encoder(AudioEncoder::Disabled);
         }
 
-        let min_rate = format!("{}K", config.min_rate.unwrap_or(500));
-        let max_rate_int = config.max_rate.unwrap_or(4000);
-        let max_rate = format!("{}K", max_rate_int);
-        let avg_rate = format!("{}K", config.avg_rate.unwrap_or(max_rate_int));
-
-        let ffmpeg_args = vec![
-            "-b:v", avg_rate.as_str(),
-            "-minrate:v", min_rate.as_str(),
-            "-maxrate:v", max_rate.as_str(),
-            "-bufsize:v", "10M",
-            "-preset", "fast",
-        ];
-
-        if let Err(e) = ffmpeg_stream.start(ffmpeg_args) {
-            eprintln!("Couldn't start FFmpeg {e}, retrying...");
-            sleep(Duration::from_secs(3)).await;
+        let mut ffmpeg_stream = FFmpeg::new();
+        if let Err(e) = ffmpeg_stream.start(command) {
+            if selected_encoder.as_ref().is_some_and(|encoder| encoder.caused(&e.to_string())) {
+                selected_encoder = None;
+            }
+            if !supervisor.wait_for_restart(RestartReason::StartFailed(e.to_string()), &mut shutdown).await {
+                return Ok(());
+            }
             continue;
         }
+        supervisor.started();
+        log_progress(ffmpeg_stream.progress(), PROGRESS_LOG_INTERVAL);
+        if feed_audio_input {
+            // Switches to the source without restarting FFmpeg, ends by itself once FFmpeg is gone
+            if let Some(audio_input) = ffmpeg_stream.take_stdin() {
+                spawn(feed_audio(audio_input, config.audio_pat.clone(), AUDIO_SOURCE_POLL_INTERVAL));
+            }
+        }
         
-        ffmpeg_stream.wait_until_end().await?;
+        let reason = select! {
+            result = ffmpeg_stream.wait_until_end() => {
+                match result {
+                    Ok(exit_status) => RestartReason::Failed(format!("exited with {exit_status}")),
+                    Err(e) => RestartReason::Failed(e.to_string())
+                }
+            }
+            _ = wait_for_audio_source(&config.audio_pat), if audio_missing => {
+                // The stream needs a new audio track, which FFmpeg can't add while running
+                println!("Audio input appeared, restarting FFmpeg with it");
+                ffmpeg_stream.stop_gracefully(STOP_GRACE_PERIOD).await?;
+                continue;
+            }
+            _ = shutdown.wait() => {
+                ffmpeg_stream.stop_gracefully(STOP_GRACE_PERIOD).await?;
+                return Ok(());
+            }
+        };
+        // Output problems like an RTMP disconnect don't need the encoders probed again
+        if let RestartReason::Failed(error) = &reason {
+            if selected_encoder.as_ref().is_some_and(|encoder| encoder.caused(error)) {
+                selected_encoder = None;
+            }
+        }
+        if !supervisor.wait_for_restart(reason, &mut shutdown).await {
+            return Ok(());
+        }
     }
 }
@@ -1,31 +1,313 @@
-use std::str::{from_utf8, Utf8Error};
+use std::{fmt, io::ErrorKind, os::fd::{AsRawFd, RawFd}, str::{from_utf8, Utf8Error}, time::{SystemTime, UNIX_EPOCH}};
 
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
-use anyhow::{anyhow, Ok};
+use tokio::{io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf}, net::TcpStream};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsAcceptor, TlsConnector};
+use anyhow::anyhow;
+
+/// Identifies the start of every AllVu packet on the wire
+pub const PACKET_MAGIC: [u8; 2] = *b"AV";
+/// Wire format version, bumped whenever the header or a payload layout changes
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Magic (2) + version (1) + packet type (1) + flags (1) + length (4)
+pub const HEADER_SIZE: usize = 9;
+
+/// Sequence number (8) + sender timestamp (8) in front of video data
+pub const VIDEO_HEADER_SIZE: usize = 16;
+
+/// Set on the first `VideoStream` packet of a new encoder run. The
+/// data following it starts with a fresh FLV header.
+pub const STREAM_START_FLAG: u8 = 0x01;
+
+/// Largest payload a single packet may carry. Anything above this is
+/// treated as a corrupted stream instead of being allocated.
+pub const MAX_PACKET_SIZE: u32 = 4 * 1024 * 1024;
 
 #[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum PacketType {
     InitialGreet = 1,
     NewSession = 2,
     ExistingSession = 3,
+    /// Refuses a session request, carries a `RejectReason` code followed by a message
+    SessionRejected = 4,
+    /// Random challenge the client has to answer with an `AuthResponse`
+    AuthChallenge = 5,
+    /// HMAC of the challenge keyed with the shared password
+    AuthResponse = 6,
+    /// Asks for the rest of the connection to run over TLS, the other side answers with the same type
+    StartTls = 7,
     ReadyForTransmission = 10,
     VideoStream = 20,
+    /// Carries the sender's timestamp, answered with a `HeartbeatAck`
+    Heartbeat = 30,
+    /// Echoes the payload of a `Heartbeat` back to its sender
+    HeartbeatAck = 31,
     CloseConnection = 100,
 }
 
-pub struct ConnectionPacket {
+#[derive(Debug)]
+pub struct UnknownPacketType(pub u8);
+
+impl fmt::Display for UnknownPacketType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown packet type {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownPacketType {}
+
+impl TryFrom<u8> for PacketType {
+    type Error = UnknownPacketType;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(PacketType::InitialGreet),
+            2 => Ok(PacketType::NewSession),
+            3 => Ok(PacketType::ExistingSession),
+            4 => Ok(PacketType::SessionRejected),
+            5 => Ok(PacketType::AuthChallenge),
+            6 => Ok(PacketType::AuthResponse),
+            7 => Ok(PacketType::StartTls),
+            10 => Ok(PacketType::ReadyForTransmission),
+            20 => Ok(PacketType::VideoStream),
+            30 => Ok(PacketType::Heartbeat),
+            31 => Ok(PacketType::HeartbeatAck),
+            100 => Ok(PacketType::CloseConnection),
+            _ => Err(UnknownPacketType(value))
+        }
+    }
+}
+
+///
+/// Why the server refused a connection, lets the client tell a session
+/// it can replace from a problem that retrying won't fix
+/// 
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RejectReason {
+    /// The session token is unknown or its session expired
+    UnknownSession = 1,
+    /// The client couldn't prove it knows the password
+    AuthFailed = 2,
+    /// Client and server don't agree on using TLS
+    Encryption = 3
+}
+
+impl TryFrom<u8> for RejectReason {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(RejectReason::UnknownSession),
+            2 => Ok(RejectReason::AuthFailed),
+            3 => Ok(RejectReason::Encryption),
+            _ => Err(anyhow!("Unknown reject reason {value}"))
+        }
+    }
+}
+
+///
+/// Fixed size header that precedes every packet. All multi-byte
+/// fields are encoded in network (big-endian) byte order so that
+/// peers of different endianness agree on the frame size.
+/// 
+pub struct PacketHeader {
+    pub version: u8,
     pub packet_type: u8,
+    pub flags: u8,
+    pub length: u32
+}
+
+#[derive(Debug)]
+pub enum HeaderError {
+    BadMagic([u8; 2]),
+    UnsupportedVersion(u8)
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::BadMagic(magic) => write!(f, "Invalid packet magic {magic:?}"),
+            HeaderError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported protocol version {version}, expected {PROTOCOL_VERSION}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+impl PacketHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut header_bytes = [0u8; HEADER_SIZE];
+        header_bytes[0..2].copy_from_slice(&PACKET_MAGIC);
+        header_bytes[2] = self.version;
+        header_bytes[3] = self.packet_type;
+        header_bytes[4] = self.flags;
+        header_bytes[5..9].copy_from_slice(&self.length.to_be_bytes());
+
+        header_bytes
+    }
+
+    pub fn from_bytes(header_bytes: &[u8; HEADER_SIZE]) -> Result<Self, HeaderError> {
+        let magic = [header_bytes[0], header_bytes[1]];
+        if magic != PACKET_MAGIC {
+            return Err(HeaderError::BadMagic(magic));
+        }
+
+        let version = header_bytes[2];
+        if version != PROTOCOL_VERSION {
+            return Err(HeaderError::UnsupportedVersion(version));
+        }
+
+        Ok(Self {
+            version,
+            packet_type: header_bytes[3],
+            flags: header_bytes[4],
+            length: u32::from_be_bytes([header_bytes[5], header_bytes[6], header_bytes[7], header_bytes[8]])
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum FramingError {
+    /// The peer closed the connection between two packets
+    Closed,
+    /// The peer closed the connection in the middle of a packet
+    Truncated { expected: usize, received: usize },
+    /// The packet header announced a payload bigger than `MAX_PACKET_SIZE`
+    Oversized(u32),
+    /// The packet header couldn't be decoded
+    Header(HeaderError),
+    Io(std::io::Error)
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FramingError::Closed => write!(f, "Connection closed"),
+            FramingError::Truncated { expected, received } => {
+                write!(f, "Truncated packet, expected {expected} bytes but received {received}")
+            }
+            FramingError::Oversized(size) => {
+                write!(f, "Packet of size {size} exceeds the maximum of {MAX_PACKET_SIZE} bytes")
+            }
+            FramingError::Header(e) => write!(f, "Invalid packet header: {e}"),
+            FramingError::Io(e) => write!(f, "Connection error: {e}")
+        }
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+pub struct ConnectionPacket {
+    pub packet_type: PacketType,
+    pub flags: u8,
     pub packet_data: Vec<u8>
 }
 
+///
+/// Payload of a `VideoStream` packet. The sequence number lets the
+/// receiver put chunks back in order after they travelled over
+/// different connections, and the timestamp (microseconds since the
+/// UNIX epoch on the sender) is used for latency measurement.
+/// 
+pub struct VideoChunk {
+    pub sequence: u64,
+    pub timestamp: u64,
+    /// The chunk starts a new stream, see `STREAM_START_FLAG`
+    pub stream_start: bool,
+    pub data: Vec<u8>
+}
+
+///
+/// Bytes the kernel still holds in the socket's send queue, either not
+/// sent yet or not acknowledged by the peer
+/// 
+pub fn queued_bytes(socket_fd: RawFd) -> Option<usize> {
+    let mut queued: libc::c_int = 0;
+    // SAFETY: TIOCOUTQ only writes an int to `queued`, a bad fd just makes it fail
+    let result = unsafe { libc::ioctl(socket_fd, libc::TIOCOUTQ, &mut queued) };
+    if result < 0 {
+        return None;
+    }
+    Some(queued as usize)
+}
+
+pub fn timestamp_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0)
+}
+
 impl ConnectionPacket {
+    pub fn video_stream(sequence: u64, stream_start: bool, data: &[u8]) -> Self {
+        let mut packet_data: Vec<u8> = Vec::with_capacity(VIDEO_HEADER_SIZE + data.len());
+        packet_data.extend_from_slice(&sequence.to_be_bytes());
+        packet_data.extend_from_slice(&timestamp_micros().to_be_bytes());
+        packet_data.extend_from_slice(data);
+
+        Self {
+            packet_type: PacketType::VideoStream,
+            flags: if stream_start { STREAM_START_FLAG } else { 0 },
+            packet_data
+        }
+    }
+
+    pub fn to_video_chunk(&self) -> anyhow::Result<VideoChunk> {
+        if self.packet_type != PacketType::VideoStream {
+            return Err(anyhow!("Not a video stream packet"));
+        }
+        if self.packet_data.len() < VIDEO_HEADER_SIZE {
+            return Err(anyhow!("Video stream packet too short"));
+        }
+
+        let (sequence_bytes, rest) = self.packet_data.split_at(8);
+        let (timestamp_bytes, data) = rest.split_at(8);
+        Ok(VideoChunk {
+            sequence: u64::from_be_bytes(sequence_bytes.try_into()?),
+            timestamp: u64::from_be_bytes(timestamp_bytes.try_into()?),
+            stream_start: self.flags & STREAM_START_FLAG != 0,
+            data: data.to_vec()
+        })
+    }
+
+    pub fn session_rejected(reason: RejectReason, message: &str) -> Self {
+        let mut packet_data = vec![reason as u8];
+        packet_data.extend_from_slice(message.as_bytes());
+
+        Self {
+            packet_type: PacketType::SessionRejected,
+            flags: 0,
+            packet_data
+        }
+    }
+
+    /// Splits a `SessionRejected` packet into its reason and message
+    pub fn to_rejection(&self) -> anyhow::Result<(RejectReason, &str)> {
+        if self.packet_type != PacketType::SessionRejected {
+            return Err(anyhow!("Not a session rejection packet"));
+        }
+        let Some((&reason, message)) = self.packet_data.split_first() else {
+            return Err(anyhow!("Session rejection packet is empty"));
+        };
+        Ok((RejectReason::try_from(reason)?, from_utf8(message)?))
+    }
+
+    pub fn header(&self) -> PacketHeader {
+        PacketHeader {
+            version: PROTOCOL_VERSION,
+            packet_type: self.packet_type as u8,
+            flags: self.flags,
+            length: self.packet_data.len() as u32
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut packet_bytes: Vec<u8> = Vec::new();
-        packet_bytes.push(self.packet_type);
-        let packet_length: u32 = self.packet_data.len() as u32;
-        let packet_length_bytes = u32::to_ne_bytes(packet_length);
-        packet_bytes.append(&mut packet_length_bytes.to_vec());
-        packet_bytes.append(&mut self.packet_data.clone());
+        let mut packet_bytes: Vec<u8> = Vec::with_capacity(HEADER_SIZE + self.packet_data.len());
+        packet_bytes.extend_from_slice(&self.header().to_bytes());
+        packet_bytes.extend_from_slice(&self.packet_data);
 
         packet_bytes
     }
@@ -38,50 +320,322 @@ impl ConnectionPacket {
 impl Clone for ConnectionPacket {
     fn clone(&self) -> Self {
         Self { 
-            packet_type: self.packet_type.clone(), 
+            packet_type: self.packet_type, 
+            flags: self.flags,
             packet_data: self.packet_data.clone() 
         }
     }
 }
 
+///
+/// Fills the whole buffer from the reader, no matter how many
+/// reads it takes. Returns how many bytes were read before the
+/// peer closed the connection if it did so early.
+/// 
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8]) -> Result<(), (usize, std::io::Error)> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]).await {
+            Ok(0) => {
+                return Err((filled, ErrorKind::UnexpectedEof.into()));
+            }
+            Ok(bytes_read) => {
+                filled += bytes_read;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => {
+                return Err((filled, e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Reads a single length-prefixed packet. Short reads are retried
+/// until the whole frame has arrived, so a packet split across
+/// several TCP segments is reassembled correctly.
+/// 
+/// Packets of a type this version doesn't know about are logged and
+/// dropped, which keeps older peers working when new packet types
+/// get added to the protocol.
+/// 
+pub async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> Result<ConnectionPacket, FramingError> {
+    loop {
+        let (header, packet_data) = read_frame(reader).await?;
+        match PacketType::try_from(header.packet_type) {
+            Ok(packet_type) => {
+                return Ok(ConnectionPacket {
+                    packet_type,
+                    flags: header.flags,
+                    packet_data
+                });
+            }
+            Err(e) => {
+                eprintln!("{e}, dropping {} bytes", packet_data.len());
+            }
+        }
+    }
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(PacketHeader, Vec<u8>), FramingError> {
+    let mut header_bytes = [0u8; HEADER_SIZE];
+    if let Err((received, e)) = read_full(reader, &mut header_bytes).await {
+        return Err(match e.kind() {
+            ErrorKind::UnexpectedEof if received == 0 => FramingError::Closed,
+            ErrorKind::UnexpectedEof => FramingError::Truncated { expected: header_bytes.len(), received },
+            _ => FramingError::Io(e)
+        });
+    }
+
+    let header = PacketHeader::from_bytes(&header_bytes).map_err(FramingError::Header)?;
+    if header.length > MAX_PACKET_SIZE {
+        return Err(FramingError::Oversized(header.length));
+    }
+
+    let mut packet_data = vec![0u8; header.length as usize];
+    if let Err((received, e)) = read_full(reader, &mut packet_data).await {
+        return Err(match e.kind() {
+            ErrorKind::UnexpectedEof => FramingError::Truncated { expected: packet_data.len(), received },
+            _ => FramingError::Io(e)
+        });
+    }
+
+    Ok((header, packet_data))
+}
+
+/// Anything a connection can run over, a plain TCP stream or a TLS session
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
 pub struct Connection {
-    pub tcp_stream: TcpStream,
+    stream: Box<dyn AsyncStream>,
+    /// The underlying TCP socket, kept to query its send queue
+    socket_fd: Option<RawFd>,
+    /// Identifies the connection in logs, e.g. the network interface it goes through
+    pub label: String,
     pub penalty: u32
 }
 
 impl Connection {
     pub fn new(tcp_stream: TcpStream) -> Self {
-        let connection = Self {
-            tcp_stream,
+        let label = tcp_stream.peer_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default();
+        let socket_fd = Some(tcp_stream.as_raw_fd());
+        Self {
+            stream: Box::new(tcp_stream),
+            socket_fd,
+            label,
             penalty: 0
-        };
-        connection
+        }
+    }
+
+    pub fn socket_fd(&self) -> Option<RawFd> {
+        self.socket_fd
     }
 
     pub async fn read(&mut self) -> anyhow::Result<ConnectionPacket> {
-        let mut packet_type_bytes = [0u8];
-        let bytes_received = self.tcp_stream.read(&mut packet_type_bytes).await?;
-        if bytes_received == 0 {
-            return Err(anyhow!("Connection closed"));
-        }
+        let packet = read_packet(&mut self.stream).await?;
+        Ok(packet)
+    }
 
-        let mut packet_size_bytes = [0u8; 4];
-        self.tcp_stream.read(&mut packet_size_bytes).await?;
-        let packet_size = u32::from_ne_bytes(packet_size_bytes);
-        let mut packet_data: Vec<u8> = Vec::new();
-        packet_data.resize(packet_size as usize, 0);
-        self.tcp_stream.read(packet_data.as_mut_slice()).await?;
+    pub async fn write(&mut self, packet: ConnectionPacket) -> anyhow::Result<()> {
+        let bytes = packet.to_bytes();
+        self.stream.write_all(&bytes).await?;
+        self.stream.flush().await?;
 
-        Ok(ConnectionPacket {
-            packet_type: packet_type_bytes[0],
-            packet_data
+        Ok(())
+    }
+
+    /// Wraps the connection in TLS as the client, verifying the server's certificate
+    pub async fn start_tls_client(self, connector: &TlsConnector, server_name: ServerName<'static>) -> anyhow::Result<Self> {
+        let tls_stream = connector.connect(server_name, self.stream).await?;
+        Ok(Self {
+            stream: Box::new(tls_stream),
+            socket_fd: self.socket_fd,
+            label: self.label,
+            penalty: self.penalty
+        })
+    }
+
+    /// Wraps the connection in TLS as the server
+    pub async fn start_tls_server(self, acceptor: &TlsAcceptor) -> anyhow::Result<Self> {
+        let tls_stream = acceptor.accept(self.stream).await?;
+        Ok(Self {
+            stream: Box::new(tls_stream),
+            socket_fd: self.socket_fd,
+            label: self.label,
+            penalty: self.penalty
         })
     }
 
+    ///
+    /// Splits the connection so that one task can keep waiting for
+    /// incoming packets while others write to it.
+    /// 
+    pub fn into_split(self) -> (ConnectionReader, ConnectionWriter) {
+        let (read_half, write_half) = split(self.stream);
+        (ConnectionReader { read_half }, ConnectionWriter { write_half })
+    }
+}
+
+pub struct ConnectionReader {
+    read_half: ReadHalf<Box<dyn AsyncStream>>
+}
+
+impl ConnectionReader {
+    pub async fn read(&mut self) -> anyhow::Result<ConnectionPacket> {
+        let packet = read_packet(&mut self.read_half).await?;
+        Ok(packet)
+    }
+}
+
+pub struct ConnectionWriter {
+    write_half: WriteHalf<Box<dyn AsyncStream>>
+}
+
+impl ConnectionWriter {
     pub async fn write(&mut self, packet: ConnectionPacket) -> anyhow::Result<()> {
         let bytes = packet.to_bytes();
-        self.tcp_stream.write_all(&bytes).await?;
+        self.write_half.write_all(&bytes).await?;
+        self.write_half.flush().await?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{pin::Pin, task::{Context, Poll}};
+    use tokio::io::{duplex, ReadBuf};
+    use super::*;
+
+    /// Hands out the data one byte per read, like a very fragmented stream
+    struct OneByteReader {
+        data: Vec<u8>,
+        position: usize
+    }
+
+    impl AsyncRead for OneByteReader {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            if let Some(&byte) = self.data.get(self.position) {
+                buf.put_slice(&[byte]);
+                self.position += 1;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn packet(packet_type: PacketType, packet_data: &[u8]) -> Vec<u8> {
+        ConnectionPacket { packet_type, flags: 0, packet_data: packet_data.to_vec() }.to_bytes()
+    }
+
+    #[tokio::test]
+    async fn reassembles_a_frame_split_across_reads() {
+        let mut bytes = packet(PacketType::InitialGreet, b"ALLVU-CLIENT-1.0.0");
+        bytes.extend(packet(PacketType::VideoStream, &[7; 600]));
+        let mut reader = OneByteReader { data: bytes, position: 0 };
+
+        let greet = read_packet(&mut reader).await.unwrap();
+        assert_eq!(greet.packet_type, PacketType::InitialGreet);
+        assert_eq!(greet.to_string().unwrap(), "ALLVU-CLIENT-1.0.0");
+        let video = read_packet(&mut reader).await.unwrap();
+        assert_eq!(video.packet_type, PacketType::VideoStream);
+        assert_eq!(video.packet_data, vec![7; 600]);
+        assert!(matches!(read_packet(&mut reader).await, Err(FramingError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn reports_a_clean_close() {
+        let (client, mut server) = duplex(64);
+        drop(client);
+        assert!(matches!(read_packet(&mut server).await, Err(FramingError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn reports_a_truncated_header() {
+        let (mut client, mut server) = duplex(64);
+        client.write_all(&packet(PacketType::Heartbeat, &[])[..4]).await.unwrap();
+        drop(client);
+        assert!(matches!(
+            read_packet(&mut server).await,
+            Err(FramingError::Truncated { expected: HEADER_SIZE, received: 4 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_a_truncated_payload() {
+        let (mut client, mut server) = duplex(64);
+        let bytes = packet(PacketType::VideoStream, &[1; 20]);
+        client.write_all(&bytes[..HEADER_SIZE + 5]).await.unwrap();
+        drop(client);
+        assert!(matches!(
+            read_packet(&mut server).await,
+            Err(FramingError::Truncated { expected: 20, received: 5 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_packets() {
+        let header = PacketHeader {
+            version: PROTOCOL_VERSION,
+            packet_type: PacketType::VideoStream as u8,
+            flags: 0,
+            length: MAX_PACKET_SIZE + 1
+        };
+        let mut reader = OneByteReader { data: header.to_bytes().to_vec(), position: 0 };
+        assert!(matches!(read_packet(&mut reader).await, Err(FramingError::Oversized(size)) if size == MAX_PACKET_SIZE + 1));
+    }
+
+    #[tokio::test]
+    async fn drops_unknown_packet_types() {
+        let mut bytes = packet(PacketType::Heartbeat, b"first");
+        bytes[3] = 99;
+        bytes.extend(packet(PacketType::HeartbeatAck, b"second"));
+        let mut reader = OneByteReader { data: bytes, position: 0 };
+
+        let packet = read_packet(&mut reader).await.unwrap();
+        assert_eq!(packet.packet_type, PacketType::HeartbeatAck);
+        assert_eq!(packet.packet_data, b"second");
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut bytes = packet(PacketType::Heartbeat, &[]);
+        bytes[2] = PROTOCOL_VERSION + 1;
+        let header_bytes: [u8; HEADER_SIZE] = bytes[..HEADER_SIZE].try_into().unwrap();
+        assert!(matches!(PacketHeader::from_bytes(&header_bytes), Err(HeaderError::UnsupportedVersion(_))));
+
+        bytes[0] = b'X';
+        let header_bytes: [u8; HEADER_SIZE] = bytes[..HEADER_SIZE].try_into().unwrap();
+        assert!(matches!(PacketHeader::from_bytes(&header_bytes), Err(HeaderError::BadMagic(_))));
+    }
+
+    #[test]
+    fn video_chunks_keep_the_stream_start_flag() {
+        let chunk = ConnectionPacket::video_stream(42, true, b"FLV").to_video_chunk().unwrap();
+        assert_eq!(chunk.sequence, 42);
+        assert!(chunk.stream_start);
+        assert_eq!(chunk.data, b"FLV");
+        assert!(!ConnectionPacket::video_stream(43, false, &[]).to_video_chunk().unwrap().stream_start);
+    }
+
+    #[test]
+    fn rejections_carry_their_reason() {
+        let packet = ConnectionPacket::session_rejected(RejectReason::AuthFailed, "Authentication failed");
+        let (reason, message) = packet.to_rejection().unwrap();
+        assert_eq!(reason, RejectReason::AuthFailed);
+        assert_eq!(message, "Authentication failed");
+
+        let unknown_reason = ConnectionPacket {
+            packet_type: PacketType::SessionRejected,
+            flags: 0,
+            packet_data: vec![200]
+        };
+        assert!(unknown_reason.to_rejection().is_err());
+        assert!(ConnectionPacket::video_stream(0, false, &[]).to_rejection().is_err());
+    }
+}
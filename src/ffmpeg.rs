@@ -1,186 +1,1128 @@
-use std::{io::Cursor, path::PathBuf, process::{ExitStatus, Stdio}};
+use std::{fmt::Display, io::Cursor, path::PathBuf, process::{ExitStatus, Stdio}, time::Duration};
 use anyhow::{anyhow, Result};
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, process::{Child, Command}, select, spawn, sync::oneshot};
+use serde::Deserialize;
+use tokio::{io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader}, process::{Child, ChildStdin, Command}, select, spawn, sync::{broadcast::{self, error::RecvError}, mpsc}, time::{interval, timeout}};
 
 const CHUNK_SIZE: usize = 500;
+/// Half a second of test video, encoded when probing an encoder
+const PROBE_SOURCE: &str = "testsrc2=duration=0.5:size=640x360:rate=30";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Format of raw audio passed between processes, signed 16 bit little endian samples
+pub const PCM_SAMPLE_RATE: u32 = 48000;
+pub const PCM_CHANNELS: u32 = 2;
 
+#[derive(Clone, Copy, Debug)]
 pub enum OutputType {
     // Used for RTMP
     FLV,
     MP4,
     /// Used for SRT, MPEG Transport Stream https://en.wikipedia.org/wiki/MPEG_transport_stream
-    MPEGTS 
+    MPEGTS,
+    /// Raw audio in the `PCM_SAMPLE_RATE` and `PCM_CHANNELS` format, has no video
+    PCM
+}
+
+impl OutputType {
+    ///
+    /// Whether the container format can carry the codec. FLV only has
+    /// H264, HEVC, AV1 and VP9 come with enhanced FLV, whose muxer is
+    /// only in FFmpeg 6.1 or newer. That's why `probe_encoder` encodes
+    /// into the real container.
+    /// 
+    pub fn supports(&self, codec: VideoCodec) -> bool {
+        match self {
+            OutputType::FLV => matches!(codec, VideoCodec::H264) || self.supports_enhanced(codec),
+            OutputType::MP4 => true,
+            OutputType::MPEGTS => matches!(codec, VideoCodec::H264 | VideoCodec::HEVC),
+            OutputType::PCM => false
+        }
+    }
+
+    /// Codecs enhanced FLV added, they need FFmpeg 6.1 and an RTMP server that knows them
+    fn supports_enhanced(&self, codec: VideoCodec) -> bool {
+        matches!(self, OutputType::FLV) && matches!(codec, VideoCodec::HEVC | VideoCodec::AV1 | VideoCodec::VP9)
+    }
+
+    pub fn supports_opus(&self) -> bool {
+        matches!(self, OutputType::MP4 | OutputType::MPEGTS)
+    }
 }
 
 #[derive(PartialEq)]
 pub enum InputType {
     V4L2,
     PulseAudio,
+    /// FLV stream, used when the input is piped in
+    FLV,
+    /// Generated by a libavfilter source, like `testsrc2`
+    Lavfi,
+    /// Raw audio in the `PCM_SAMPLE_RATE` and `PCM_CHANNELS` format
+    PCM,
     AutoDetect
 }
 
 pub struct Input {
-    pub path: String,
-    pub input_type: InputType
+    path: String,
+    input_type: InputType,
+    framerate: Option<u32>,
+    video_size: Option<(u32, u32)>,
+    pixel_format: Option<String>,
+    thread_queue_size: Option<u32>
+}
+
+impl Input {
+    pub fn new(path: impl Into<String>, input_type: InputType) -> Self {
+        Self {
+            path: path.into(),
+            input_type,
+            framerate: None,
+            video_size: None,
+            pixel_format: None,
+            thread_queue_size: None
+        }
+    }
+
+    pub fn framerate(mut self, framerate: u32) -> Self {
+        self.framerate = Some(framerate);
+        self
+    }
+
+    pub fn video_size(mut self, width: u32, height: u32) -> Self {
+        self.video_size = Some((width, height));
+        self
+    }
+
+    pub fn pixel_format(mut self, pixel_format: impl Into<String>) -> Self {
+        self.pixel_format = Some(pixel_format.into());
+        self
+    }
+
+    /// Packets queued for this input, live inputs need more of them
+    /// when another input is slow to start
+    pub fn thread_queue_size(mut self, thread_queue_size: u32) -> Self {
+        self.thread_queue_size = Some(thread_queue_size);
+        self
+    }
+
+    fn push_args(&self, args: &mut Vec<String>) {
+        match self.input_type {
+            InputType::V4L2 => {
+                args.extend(["-f".into(), "video4linux2".into()]);
+            }
+            InputType::PulseAudio => {
+                args.extend(["-f".into(), "pulse".into()]);
+            }
+            InputType::FLV => {
+                args.extend(["-f".into(), "flv".into()]);
+            }
+            InputType::Lavfi => {
+                args.extend(["-f".into(), "lavfi".into()]);
+            }
+            InputType::PCM => {
+                args.extend(["-f".into(), "s16le".into()]);
+                args.extend(["-ar".into(), PCM_SAMPLE_RATE.to_string(), "-ac".into(), PCM_CHANNELS.to_string()]);
+            }
+            InputType::AutoDetect => {}
+        }
+        if let Some(framerate) = self.framerate {
+            args.extend(["-framerate".into(), framerate.to_string()]);
+        }
+        if let Some((width, height)) = self.video_size {
+            args.extend(["-video_size".into(), format!("{width}x{height}")]);
+        }
+        if let Some(pixel_format) = &self.pixel_format {
+            args.extend(["-pixel_format".into(), pixel_format.clone()]);
+        }
+        if let Some(thread_queue_size) = self.thread_queue_size {
+            args.extend(["-thread_queue_size".into(), thread_queue_size.to_string()]);
+        }
+        args.extend(["-i".into(), self.path.clone()]);
+    }
 }
 
 pub struct Output {
-    pub path: String,
-    pub output_type: OutputType
+    path: String,
+    output_type: OutputType,
+    ts_offset: Option<Duration>
 }
 
+impl Output {
+    pub fn new(path: impl Into<String>, output_type: OutputType) -> Self {
+        Self {
+            path: path.into(),
+            output_type,
+            ts_offset: None
+        }
+    }
+
+    /// Shifts the output timestamps, so a restarted encoder continues
+    /// where the previous one stopped
+    pub fn ts_offset(mut self, ts_offset: Duration) -> Self {
+        self.ts_offset = Some(ts_offset);
+        self
+    }
+
+    fn push_args(&self, args: &mut Vec<String>) {
+        if let Some(ts_offset) = self.ts_offset {
+            args.extend(["-output_ts_offset".into(), format!("{:.3}", ts_offset.as_secs_f64())]);
+        }
+        args.push("-f".into());
+        match self.output_type {
+            OutputType::FLV => {
+                args.push("flv".into());
+            }
+            OutputType::MP4 => {
+                args.push("mp4".into());
+            }
+            OutputType::MPEGTS => {
+                args.push("mpegts".into());
+            }
+            OutputType::PCM => {
+                args.push("s16le".into());
+            }
+        }
+        args.push(self.path.clone());
+    }
+}
+
+///
+/// Rate control and keyframes of the video encoder, bitrates are in
+/// kbit/s. Unset values are left to the encoder's defaults.
+/// 
+#[derive(Default, Clone)]
+pub struct VideoSettings {
+    pub bitrate: Option<usize>,
+    pub min_rate: Option<usize>,
+    pub max_rate: Option<usize>,
+    pub buffer_size: Option<usize>,
+    /// Frames between keyframes
+    pub gop: Option<u32>,
+    /// Forces a keyframe this often, regardless of the framerate
+    pub keyframe_interval: Option<Duration>
+}
+
+impl VideoSettings {
+    fn push_args(&self, args: &mut Vec<String>) {
+        let rates = [
+            ("-b:v", self.bitrate),
+            ("-minrate:v", self.min_rate),
+            ("-maxrate:v", self.max_rate),
+            ("-bufsize:v", self.buffer_size)
+        ];
+        for (flag, rate) in rates {
+            if let Some(rate) = rate {
+                args.extend([flag.into(), format!("{rate}K")]);
+            }
+        }
+        if let Some(gop) = self.gop {
+            args.extend(["-g".into(), gop.to_string()]);
+        }
+        if let Some(keyframe_interval) = self.keyframe_interval {
+            args.extend(["-force_key_frames".into(), format!("expr:gte(t,n_forced*{:.3})", keyframe_interval.as_secs_f64())]);
+        }
+    }
+}
+
+///
+/// Resolution, framerate and deinterlacing of the encoded video. With
+/// only one of width and height set, the other one keeps the aspect
+/// ratio.
+/// 
+#[derive(Default, Clone)]
+pub struct VideoFormat {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub framerate: Option<u32>,
+    pub deinterlace: bool
+}
+
+impl VideoFormat {
+    ///
+    /// Filters producing the format. With VAAPI they run on the GPU,
+    /// after the frames were uploaded.
+    /// 
+    fn filters(&self, vaapi: bool) -> Vec<String> {
+        let mut filters = Vec::new();
+        if self.deinterlace {
+            filters.push(if vaapi { "deinterlace_vaapi" } else { "yadif" }.into());
+        }
+        if self.width.is_some() || self.height.is_some() {
+            // -2 keeps the aspect ratio, rounded to an even size
+            let width = self.width.map(|width| width as i64).unwrap_or(-2);
+            let height = self.height.map(|height| height as i64).unwrap_or(-2);
+            if vaapi {
+                filters.push(format!("scale_vaapi=w={width}:h={height}"));
+            } else {
+                filters.push(format!("scale={width}:{height}"));
+            }
+        }
+        if let Some(framerate) = self.framerate {
+            filters.push(format!("fps={framerate}"));
+        }
+
+        filters
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum VaapiRateControl {
+    /// Constant quantizer, set with `qp`
+    CQP,
+    CBR,
+    VBR,
+    /// Quality defined VBR, not supported by every driver
+    QVBR,
+    AVBR,
+    /// Intelligent constant quality, Intel only
+    ICQ
+}
+
+///
+/// Settings of the VAAPI encoders, `-preset` and `-tune` don't exist
+/// for them
+/// 
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct VaapiSettings {
+    pub rc_mode: Option<VaapiRateControl>,
+    /// Quantizer used with the CQP rate control
+    pub qp: Option<u32>,
+    /// Intra frames between IDR frames, clients can only join the stream on an IDR frame
+    pub idr_interval: Option<u32>,
+    /// B-frames between reference frames, 0 lowers the latency
+    pub b_frames: Option<u32>,
+    pub profile: Option<String>
+}
+
+impl VaapiSettings {
+    fn push_args(&self, args: &mut Vec<String>) {
+        if let Some(rc_mode) = self.rc_mode {
+            args.extend(["-rc_mode".into(), format!("{:?}", rc_mode)]);
+        }
+        if let Some(qp) = self.qp {
+            args.extend(["-qp".into(), qp.to_string()]);
+        }
+        if let Some(idr_interval) = self.idr_interval {
+            args.extend(["-idr_interval".into(), idr_interval.to_string()]);
+        }
+        if let Some(b_frames) = self.b_frames {
+            args.extend(["-bf".into(), b_frames.to_string()]);
+        }
+        if let Some(profile) = &self.profile {
+            args.extend(["-profile:v".into(), profile.clone()]);
+        }
+    }
+}
+
+///
+/// Settings of libx264. `tune = "zerolatency"` drops the frame
+/// lookahead and B-frames, at the cost of quality.
+/// 
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct X264Settings {
+    pub preset: Option<String>,
+    pub tune: Option<String>,
+    pub profile: Option<String>,
+    pub b_frames: Option<u32>
+}
+
+impl Default for X264Settings {
+    fn default() -> Self {
+        Self {
+            preset: Some("fast".into()),
+            tune: None,
+            profile: None,
+            b_frames: None
+        }
+    }
+}
+
+impl X264Settings {
+    fn push_args(&self, args: &mut Vec<String>) {
+        if let Some(preset) = &self.preset {
+            args.extend(["-preset".into(), preset.clone()]);
+        }
+        if let Some(tune) = &self.tune {
+            args.extend(["-tune".into(), tune.clone()]);
+        }
+        if let Some(profile) = &self.profile {
+            args.extend(["-profile:v".into(), profile.clone()]);
+        }
+        if let Some(b_frames) = self.b_frames {
+            args.extend(["-bf".into(), b_frames.to_string()]);
+        }
+    }
+}
+
+///
+/// Settings of libsvtav1. `params` is passed as `-svtav1-params`,
+/// e.g. `"tune=0:fast-decode=1"`.
+/// 
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SvtAv1Settings {
+    /// 0 (slowest) to 13 (fastest)
+    pub preset: Option<u32>,
+    pub params: Option<String>
+}
+
+impl Default for SvtAv1Settings {
+    fn default() -> Self {
+        Self {
+            preset: Some(10),
+            params: None
+        }
+    }
+}
+
+impl SvtAv1Settings {
+    fn push_args(&self, args: &mut Vec<String>) {
+        if let Some(preset) = self.preset {
+            args.extend(["-preset".into(), preset.to_string()]);
+        }
+        if let Some(params) = &self.params {
+            args.extend(["-svtav1-params".into(), params.clone()]);
+        }
+    }
+}
+
+///
+/// Settings of libvpx-vp9, the defaults are the fastest ones usable
+/// for live streaming
+/// 
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Vp9Settings {
+    /// realtime, good or best
+    pub deadline: Option<String>,
+    /// Speed against quality, up to 8 with the realtime deadline
+    pub cpu_used: Option<i32>,
+    /// Encodes rows of the frame on separate threads
+    pub row_mt: bool
+}
+
+impl Default for Vp9Settings {
+    fn default() -> Self {
+        Self {
+            deadline: Some("realtime".into()),
+            cpu_used: Some(8),
+            row_mt: true
+        }
+    }
+}
+
+impl Vp9Settings {
+    fn push_args(&self, args: &mut Vec<String>) {
+        if let Some(deadline) = &self.deadline {
+            args.extend(["-deadline".into(), deadline.clone()]);
+        }
+        if let Some(cpu_used) = self.cpu_used {
+            args.extend(["-cpu-used".into(), cpu_used.to_string()]);
+        }
+        if self.row_mt {
+            args.extend(["-row-mt".into(), "1".into()]);
+        }
+    }
+}
+
+///
+/// Settings of every encoder, read from the config's `[vaapi]`,
+/// `[x264]`, `[x265]`, `[svtav1]` and `[vp9]` tables
+/// 
+#[derive(Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct EncoderSettings {
+    pub vaapi: VaapiSettings,
+    pub x264: X264Settings,
+    /// libx265 takes the same options as libx264
+    pub x265: X264Settings,
+    pub svtav1: SvtAv1Settings,
+    pub vp9: Vp9Settings
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VideoCodec {
+    H264,
+    HEVC,
+    AV1,
+    VP9
+}
+
+#[derive(Clone)]
 pub enum VideoEncoder {
-    SoftwareH264,
-    VAAPIH264,
-    VAAPIHEVC,
+    SoftwareH264(X264Settings),
+    SoftwareHEVC(X264Settings),
+    SoftwareAV1(SvtAv1Settings),
+    SoftwareVP9(Vp9Settings),
+    VAAPIH264(VaapiSettings),
+    VAAPIHEVC(VaapiSettings),
+    VAAPIAV1(VaapiSettings),
     Copy
 }
 
+impl VideoEncoder {
+    ///
+    /// Looks up an encoder by the name used in the configs: H264, HEVC
+    /// and AV1 are VAAPI, X264, X265, SVTAV1 and VP9 are software
+    /// encoders
+    /// 
+    pub fn from_name(name: &str, settings: &EncoderSettings) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "H264" => Some(VideoEncoder::VAAPIH264(settings.vaapi.clone())),
+            "HEVC" => Some(VideoEncoder::VAAPIHEVC(settings.vaapi.clone())),
+            "AV1" => Some(VideoEncoder::VAAPIAV1(settings.vaapi.clone())),
+            "X264" => Some(VideoEncoder::SoftwareH264(settings.x264.clone())),
+            "X265" => Some(VideoEncoder::SoftwareHEVC(settings.x265.clone())),
+            "SVTAV1" => Some(VideoEncoder::SoftwareAV1(settings.svtav1.clone())),
+            "VP9" => Some(VideoEncoder::SoftwareVP9(settings.vp9.clone())),
+            _ => None
+        }
+    }
+
+    /// FFmpeg's name of the encoder
+    pub fn codec_name(&self) -> &'static str {
+        match self {
+            VideoEncoder::SoftwareH264(_) => "libx264",
+            VideoEncoder::SoftwareHEVC(_) => "libx265",
+            VideoEncoder::SoftwareAV1(_) => "libsvtav1",
+            VideoEncoder::SoftwareVP9(_) => "libvpx-vp9",
+            VideoEncoder::VAAPIH264(_) => "h264_vaapi",
+            VideoEncoder::VAAPIHEVC(_) => "hevc_vaapi",
+            VideoEncoder::VAAPIAV1(_) => "av1_vaapi",
+            VideoEncoder::Copy => "copy"
+        }
+    }
+
+    /// The codec produced, unknown when copying
+    pub fn codec(&self) -> Option<VideoCodec> {
+        match self {
+            VideoEncoder::SoftwareH264(_) | VideoEncoder::VAAPIH264(_) => Some(VideoCodec::H264),
+            VideoEncoder::SoftwareHEVC(_) | VideoEncoder::VAAPIHEVC(_) => Some(VideoCodec::HEVC),
+            VideoEncoder::SoftwareAV1(_) | VideoEncoder::VAAPIAV1(_) => Some(VideoCodec::AV1),
+            VideoEncoder::SoftwareVP9(_) => Some(VideoCodec::VP9),
+            VideoEncoder::Copy => None
+        }
+    }
+
+    ///
+    /// Whether an FFmpeg error points at the encoder (or its device)
+    /// rather than at the inputs or the output. Only then is it worth
+    /// probing the encoders again. Muxer errors are labelled with the
+    /// output stream, e.g. `[vost#0:0/h264_vaapi @ ...]`, so only the
+    /// encoder's own context counts, not any mention of its name.
+    /// 
+    pub fn caused(&self, error: &str) -> bool {
+        let error = error.to_lowercase();
+        error.contains(&format!("[{} @", self.codec_name()))
+            || (self.uses_vaapi() && error.contains("[avhwdevicecontext @"))
+            || ENCODER_ERROR_PATTERNS.iter().any(|pattern| error.contains(pattern))
+    }
+
+    fn uses_vaapi(&self) -> bool {
+        matches!(self, VideoEncoder::VAAPIH264(_) | VideoEncoder::VAAPIHEVC(_) | VideoEncoder::VAAPIAV1(_))
+    }
+
+    fn push_args(&self, args: &mut Vec<String>) {
+        match self {
+            VideoEncoder::SoftwareH264(settings) | VideoEncoder::SoftwareHEVC(settings) => {
+                settings.push_args(args);
+            }
+            VideoEncoder::SoftwareAV1(settings) => {
+                settings.push_args(args);
+            }
+            VideoEncoder::SoftwareVP9(settings) => {
+                settings.push_args(args);
+            }
+            VideoEncoder::VAAPIH264(settings) | VideoEncoder::VAAPIHEVC(settings) | VideoEncoder::VAAPIAV1(settings) => {
+                settings.push_args(args);
+            }
+            VideoEncoder::Copy => {}
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum AudioEncoder {
     AAC,
-    Copy
+    /// Can't be sent in FLV, use it with SRT
+    Opus,
+    /// Raw samples, for `OutputType::PCM`
+    PCM,
+    Copy,
+    /// The output has no audio
+    Disabled
 }
 
-pub struct FFmpeg {
-    pub output: Option<Output>,
-    pub inputs: Vec<Input>,
-    pub video_encoder: VideoEncoder,
-    pub audio_encoder: AudioEncoder,
-    process: Option<Child>
+///
+/// Audio encoder settings, the bitrate is in kbit/s. Unset values are
+/// left to the encoder's defaults.
+/// 
+#[derive(Default, Clone)]
+pub struct AudioSettings {
+    pub bitrate: Option<usize>,
+    pub sample_rate: Option<u32>,
+    /// Fewer channels than the input has downmixes it
+    pub channels: Option<u32>
 }
 
-fn get_vaapi_renderer() -> anyhow::Result<String> {
-    let renderer_path = PathBuf::from("/dev/dri/");
-    for dir_entry_result in renderer_path.read_dir()? {
-        let Ok(dir_entry) = dir_entry_result else {
-            continue;
-        };
-        let dev_name = String::from(dir_entry.file_name().to_str().unwrap_or(""));
-        if dev_name.contains("render") {
-            return Ok(String::from(dir_entry.path().to_str().unwrap()));
+impl AudioSettings {
+    fn push_args(&self, args: &mut Vec<String>) {
+        if let Some(bitrate) = self.bitrate {
+            args.extend(["-b:a".into(), format!("{bitrate}K")]);
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            args.extend(["-ar".into(), sample_rate.to_string()]);
+        }
+        if let Some(channels) = self.channels {
+            args.extend(["-ac".into(), channels.to_string()]);
         }
     }
+}
 
-    Err(anyhow!("Renderer not found"))
+///
+/// Everything FFmpeg is started with. Inputs are added in order, their
+/// options are placed right before their `-i`.
+/// 
+pub struct FFmpegCommand {
+    inputs: Vec<Input>,
+    video_filters: Vec<String>,
+    audio_filters: Vec<String>,
+    video_encoder: VideoEncoder,
+    video_settings: VideoSettings,
+    video_format: VideoFormat,
+    audio_encoder: AudioEncoder,
+    audio_settings: AudioSettings,
+    output: Option<Output>,
+    vaapi_device: Option<String>
 }
 
-impl FFmpeg {
+impl Default for FFmpegCommand {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FFmpegCommand {
     pub fn new() -> Self {
         Self {
-            process: None,
             inputs: vec![],
-            output: None,
-            video_encoder: VideoEncoder::VAAPIH264,
+            video_filters: vec![],
+            audio_filters: vec![],
+            video_encoder: VideoEncoder::VAAPIH264(VaapiSettings::default()),
+            video_settings: VideoSettings::default(),
+            video_format: VideoFormat::default(),
             audio_encoder: AudioEncoder::AAC,
+            audio_settings: AudioSettings::default(),
+            output: None,
+            vaapi_device: None
         }
     }
 
-    pub fn start(&mut self, args: Vec<&str>) -> Result<()> {
-        // Initial args
-        let mut combined_args: Vec<&str> = vec![
+    pub fn input(mut self, input: Input) -> Self {
+        self.inputs.push(input);
+        self
+    }
+
+    /// Appends a filter to the video filter chain
+    pub fn video_filter(mut self, filter: impl Into<String>) -> Self {
+        self.video_filters.push(filter.into());
+        self
+    }
+
+    /// Appends a filter to the audio filter chain
+    pub fn audio_filter(mut self, filter: impl Into<String>) -> Self {
+        self.audio_filters.push(filter.into());
+        self
+    }
+
+    pub fn video_encoder(mut self, video_encoder: VideoEncoder) -> Self {
+        self.video_encoder = video_encoder;
+        self
+    }
+
+    pub fn video_settings(mut self, video_settings: VideoSettings) -> Self {
+        self.video_settings = video_settings;
+        self
+    }
+
+    pub fn video_format(mut self, video_format: VideoFormat) -> Self {
+        self.video_format = video_format;
+        self
+    }
+
+    pub fn audio_encoder(mut self, audio_encoder: AudioEncoder) -> Self {
+        self.audio_encoder = audio_encoder;
+        self
+    }
+
+    pub fn audio_settings(mut self, audio_settings: AudioSettings) -> Self {
+        self.audio_settings = audio_settings;
+        self
+    }
+
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Render node used by the VAAPI encoders, looked up when not set
+    pub fn vaapi_device(mut self, vaapi_device: impl Into<String>) -> Self {
+        self.vaapi_device = Some(vaapi_device.into());
+        self
+    }
+
+    fn uses_vaapi(&self) -> bool {
+        self.video_encoder.uses_vaapi()
+    }
+
+    /// The arguments FFmpeg is started with
+    pub fn to_args(&self) -> Result<Vec<String>> {
+        let mut args: Vec<String> = [
             "-hide_banner",
             "-loglevel",
-            "error"
-        ];
+            "level+warning",
+            // Progress reports share stderr with the errors, stdout may carry the output
+            "-nostats",
+            "-progress",
+            "pipe:2"
+        ].map(String::from).to_vec();
+
+        if self.uses_vaapi() {
+            let Some(vaapi_device) = &self.vaapi_device else {
+                return Err(anyhow!("VAAPI device is not set"));
+            };
+            args.extend(["-vaapi_device".into(), vaapi_device.clone()]);
+        }
 
-        // Go through all inputs
         for input in &self.inputs {
-            if input.input_type != InputType::AutoDetect {
-                combined_args.push("-f");
-                match input.input_type {
-                    InputType::V4L2 => {
-                        combined_args.push("video4linux2");
-                    }
-                    InputType::PulseAudio => {
-                        combined_args.push("pulse");
-                    }
-                    _ => {}
-                }
-            }
-            
-            combined_args.push("-i");
-            combined_args.push(&input.path);
+            input.push_args(&mut args);
         }
 
-        // Program defined args
-        combined_args.append(&mut args.clone());
+        // Video
+        let mut video_filters = self.video_filters.clone();
+        if self.uses_vaapi() {
+            video_filters.extend(["format=nv12".into(), "hwupload".into()]);
+        }
+        video_filters.extend(self.video_format.filters(self.uses_vaapi()));
+        if matches!(self.video_encoder, VideoEncoder::Copy) && !video_filters.is_empty() {
+            return Err(anyhow!("Video filters can't be used when copying the video"));
+        }
+        if !video_filters.is_empty() {
+            args.extend(["-vf".into(), video_filters.join(",")]);
+        }
+        args.extend(["-c:v".into(), self.video_encoder.codec_name().into()]);
+        if !matches!(self.video_encoder, VideoEncoder::Copy) {
+            self.video_settings.push_args(&mut args);
+            self.video_encoder.push_args(&mut args);
+        }
 
-        // Encoders
-        let renderer_device: String;
-        match self.video_encoder {
-            VideoEncoder::VAAPIH264 => {
-                renderer_device = get_vaapi_renderer()?;
-                combined_args.append(&mut vec![
-                    "-vaapi_device", &renderer_device,
-                    "-vf", "format=nv12,hwupload",
-                    "-c:v", "h264_vaapi",
-                ]);
-            }
-            VideoEncoder::VAAPIHEVC => {
-                renderer_device = get_vaapi_renderer()?;
-                combined_args.append(&mut vec![
-                    "-vaapi_device", &renderer_device,
-                    "-vf", "format=nv12,hwupload",
-                    "-c:v", "h265_vaapi",
-                ]);
-            }
-            VideoEncoder::SoftwareH264 => {
-                combined_args.append(&mut vec![
-                    "-c:v", "libx264",
-                ]);
-            }
-            VideoEncoder::Copy => {
-                combined_args.append(&mut vec![
-                    "-c:v", "copy",
-                ]);
+        // Audio
+        let codec = match self.audio_encoder {
+            AudioEncoder::AAC => "aac",
+            AudioEncoder::Opus => "libopus",
+            AudioEncoder::PCM => "pcm_s16le",
+            AudioEncoder::Copy | AudioEncoder::Disabled => {
+                if !self.audio_filters.is_empty() {
+                    return Err(anyhow!("Audio filters can't be used without encoding the audio"));
+                }
+                "copy"
             }
+        };
+        if !self.audio_filters.is_empty() {
+            args.extend(["-af".into(), self.audio_filters.join(",")]);
+        }
+        if matches!(self.audio_encoder, AudioEncoder::Disabled) {
+            args.push("-an".into());
+        } else {
+            args.extend(["-c:a".into(), codec.into()]);
         }
-
         match self.audio_encoder {
             AudioEncoder::AAC => {
-                combined_args.append(&mut vec![
-                    "-c:a", "aac",
-                ]);
+                self.audio_settings.push_args(&mut args);
+            }
+            AudioEncoder::PCM => {
+                // The format is fixed, whoever reads it relies on that
+                let audio_settings = AudioSettings {
+                    bitrate: None,
+                    sample_rate: Some(PCM_SAMPLE_RATE),
+                    channels: Some(PCM_CHANNELS)
+                };
+                audio_settings.push_args(&mut args);
             }
-            AudioEncoder::Copy => {
-                combined_args.append(&mut vec![
-                    "-c:a", "copy",
-                ]);
+            AudioEncoder::Opus => {
+                // Opus works at 48 kHz internally, other rates would be resampled twice
+                let audio_settings = AudioSettings {
+                    sample_rate: Some(self.audio_settings.sample_rate.unwrap_or(48000)),
+                    ..self.audio_settings.clone()
+                };
+                audio_settings.push_args(&mut args);
             }
+            AudioEncoder::Copy | AudioEncoder::Disabled => {}
         }
 
-        // Check if output is defined
         let Some(output) = &self.output else {
             return Err(anyhow!("Output is not defined"));
         };
+        if let Some(codec) = self.video_encoder.codec() {
+            if !output.output_type.supports(codec) {
+                return Err(anyhow!("{:?} video can't be sent in {:?}", codec, output.output_type));
+            }
+        }
+        if matches!(self.audio_encoder, AudioEncoder::Opus) && !output.output_type.supports_opus() {
+            return Err(anyhow!("Opus audio can't be sent in {:?}", output.output_type));
+        }
+        output.push_args(&mut args);
 
-        // Output type
-        combined_args.push("-f");
-        match output.output_type {
-            OutputType::FLV => {
-                combined_args.push("flv");
+        Ok(args)
+    }
+}
+
+///
+/// One block of FFmpeg's `-progress` report, sent a couple of times
+/// per second while encoding
+/// 
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    pub frame: u64,
+    pub fps: f32,
+    /// Output bitrate in kbit/s, FFmpeg doesn't know it at the start
+    pub bitrate: Option<f32>,
+    pub total_size: u64,
+    pub out_time: Duration,
+    pub dup_frames: u64,
+    pub drop_frames: u64,
+    /// Encoding speed relative to real time
+    pub speed: Option<f32>,
+    /// Set on the last report, before FFmpeg exits
+    pub finished: bool
+}
+
+/// Progress events a subscriber can fall behind by before it misses some
+const PROGRESS_CHANNEL_SIZE: usize = 64;
+
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+    Report(Progress),
+    /// FFmpeg closed its stderr, no more reports come from this process
+    Ended
+}
+
+impl Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame {} fps {:.1}", self.frame, self.fps)?;
+        if let Some(bitrate) = self.bitrate {
+            write!(f, " bitrate {:.0}K", bitrate)?;
+        }
+        if let Some(speed) = self.speed {
+            write!(f, " speed {:.2}x", speed)?;
+        }
+        write!(f, " dropped {} duplicated {} time {:.1}s", self.drop_frames, self.dup_frames, self.out_time.as_secs_f32())
+    }
+}
+
+enum ProgressLine {
+    /// A key of the current report
+    Field,
+    /// The last line of a report
+    End,
+    /// Anything else FFmpeg printed
+    Other
+}
+
+///
+/// Parses a line of the `-progress` output into the current report.
+/// Numbers FFmpeg reports as N/A are left untouched.
+/// 
+fn parse_progress_line(progress: &mut Progress, line: &str) -> ProgressLine {
+    let Some((key, value)) = line.trim().split_once('=') else {
+        return ProgressLine::Other;
+    };
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return ProgressLine::Other;
+    }
+    let value = value.trim();
+
+    match key {
+        "frame" => progress.frame = value.parse().unwrap_or(progress.frame),
+        "fps" => progress.fps = value.parse().unwrap_or(progress.fps),
+        "bitrate" => progress.bitrate = value.trim_end_matches("kbits/s").parse().ok(),
+        "total_size" => progress.total_size = value.parse().unwrap_or(progress.total_size),
+        "out_time_us" => {
+            if let Ok(micros) = value.parse::<i64>() {
+                progress.out_time = Duration::from_micros(micros.max(0) as u64);
             }
-            OutputType::MP4 => {
-                combined_args.push("mp4");
+        }
+        "dup_frames" => progress.dup_frames = value.parse().unwrap_or(progress.dup_frames),
+        "drop_frames" => progress.drop_frames = value.parse().unwrap_or(progress.drop_frames),
+        "speed" => progress.speed = value.trim_end_matches('x').parse().ok(),
+        "progress" => {
+            progress.finished = value == "end";
+            return ProgressLine::End;
+        }
+        _ => {}
+    }
+
+    ProgressLine::Field
+}
+
+/// Messages that mean the encoder itself doesn't work
+const ENCODER_ERROR_PATTERNS: [&str; 5] = [
+    "error while opening encoder",
+    "error initializing output stream",
+    "encode failed",
+    "device creation failed",
+    "renderer not found"
+];
+
+/// Messages after which FFmpeg won't recover, even if it keeps running
+const FATAL_PATTERNS: [&str; 10] = [
+    "broken pipe",
+    "device or resource busy",
+    "no such device",
+    "input/output error",
+    "connection reset",
+    "connection refused",
+    "rtmp_readpacket",
+    "rtmp_sendpacket",
+    "error muxing a packet",
+    "error submitting a packet to the muxer"
+];
+
+#[derive(PartialEq, Debug)]
+enum StderrSeverity {
+    Warning,
+    Fatal
+}
+
+///
+/// Decides whether a stderr line means FFmpeg is done for. Lines
+/// carry their log level, since FFmpeg is started with `level+`.
+/// 
+fn classify_stderr_line(line: &str) -> StderrSeverity {
+    let line = line.to_lowercase();
+    let fatal = FATAL_PATTERNS.iter().chain(ENCODER_ERROR_PATTERNS.iter()).any(|pattern| line.contains(pattern));
+    if line.contains("[fatal]") || fatal {
+        StderrSeverity::Fatal
+    } else {
+        StderrSeverity::Warning
+    }
+}
+
+///
+/// Splits FFmpeg's stderr into progress reports and log lines. Log
+/// lines are printed, fatal ones are also sent to `fatal_tx`.
+/// 
+async fn read_stderr(stderr: impl AsyncRead + Unpin, progress_tx: broadcast::Sender<ProgressEvent>, fatal_tx: mpsc::UnboundedSender<String>) {
+    let mut lines = BufReader::new(stderr).lines();
+    let mut progress = Progress::default();
+    while let Ok(Some(line)) = lines.next_line().await {
+        match parse_progress_line(&mut progress, &line) {
+            ProgressLine::Field => {}
+            ProgressLine::End => {
+                // Fails only while nobody is subscribed
+                let _ = progress_tx.send(ProgressEvent::Report(progress.clone()));
             }
-            OutputType::MPEGTS => {
-                combined_args.push("mpegts");
+            ProgressLine::Other => {
+                match classify_stderr_line(&line) {
+                    StderrSeverity::Warning => {
+                        eprintln!("FFmpeg: {line}");
+                    }
+                    StderrSeverity::Fatal => {
+                        eprintln!("FFmpeg fatal: {line}");
+                        let _ = fatal_tx.send(line);
+                    }
+                }
+            }
+        }
+    }
+    let _ = progress_tx.send(ProgressEvent::Ended);
+}
+
+///
+/// Prints the latest progress report every `period`, until the
+/// process the reports come from ends
+/// 
+pub fn log_progress(mut progress: broadcast::Receiver<ProgressEvent>, period: Duration) {
+    spawn(async move {
+        let mut latest: Option<Progress> = None;
+        let mut log_interval = interval(period);
+        log_interval.tick().await;
+        loop {
+            select! {
+                event = progress.recv() => match event {
+                    Ok(ProgressEvent::Report(report)) => latest = Some(report),
+                    // Only the latest report gets printed anyway
+                    Err(RecvError::Lagged(_)) => {}
+                    Ok(ProgressEvent::Ended) | Err(RecvError::Closed) => break
+                },
+                _ = log_interval.tick() => {
+                    if let Some(report) = latest.take() {
+                        println!("FFmpeg {report}");
+                    }
+                }
+            }
+        }
+        if let Some(report) = latest {
+            println!("FFmpeg {report}");
+        }
+    });
+}
+
+pub struct FFmpeg {
+    process: Option<Child>,
+    progress: broadcast::Sender<ProgressEvent>,
+    fatal_errors: Option<mpsc::UnboundedReceiver<String>>
+}
+
+///
+/// Checks that the encoder works on this machine: the VAAPI render node
+/// exists, FFmpeg was built with the encoder and a short test encode
+/// succeeds with its settings. The test encode goes through the
+/// output's muxer, which catches e.g. an FFmpeg without enhanced FLV.
+/// 
+pub async fn probe_encoder(encoder: &VideoEncoder, output_type: OutputType) -> Result<()> {
+    let codec_name = encoder.codec_name();
+    let mut command = FFmpegCommand::new()
+        .input(Input::new(PROBE_SOURCE, InputType::Lavfi))
+        .video_encoder(encoder.clone())
+        .audio_encoder(AudioEncoder::Disabled)
+        .output(Output::new("-", output_type));
+    if encoder.uses_vaapi() {
+        command = command.vaapi_device(get_vaapi_renderer()?);
+    }
+
+    let encoders = Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .output()
+        .await?;
+    let listed = String::from_utf8_lossy(&encoders.stdout)
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(codec_name));
+    if !listed {
+        return Err(anyhow!("FFmpeg doesn't have the {codec_name} encoder"));
+    }
+
+    let trial_encode = Command::new("ffmpeg")
+        .args(command.to_args()?)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let Ok(trial_result) = timeout(PROBE_TIMEOUT, trial_encode).await else {
+        return Err(anyhow!("Test encode with {codec_name} timed out"));
+    };
+    let trial_output = trial_result?;
+    if !trial_output.status.success() {
+        let stderr = String::from_utf8_lossy(&trial_output.stderr);
+        let error = stderr.lines().rfind(|line| !line.contains('=')).unwrap_or("no error output");
+        if encoder.codec().is_some_and(|codec| output_type.supports_enhanced(codec)) {
+            return Err(anyhow!("Test encode with {codec_name} into {output_type:?} failed, enhanced FLV needs FFmpeg 6.1 or newer: {error}"));
+        }
+        return Err(anyhow!("Test encode with {codec_name} failed: {error}"));
+    }
+
+    Ok(())
+}
+
+///
+/// Turns the configured encoder names into a fallback chain. Unknown
+/// names are a config error, they would never work.
+/// 
+pub fn encoder_chain(names: &[String], settings: &EncoderSettings) -> Result<Vec<VideoEncoder>> {
+    if names.is_empty() {
+        return Err(anyhow!("No encoders are configured"));
+    }
+    names.iter().map(|name| {
+        VideoEncoder::from_name(name, settings).ok_or_else(|| {
+            anyhow!("Unknown encoder {name}, expected H264, HEVC, AV1, X264, X265, SVTAV1 or VP9")
+        })
+    }).collect()
+}
+
+///
+/// Returns the first encoder of the chain that works on this machine
+/// and whose codec fits into the output
+/// 
+pub async fn select_encoder(encoders: &[VideoEncoder], output_type: OutputType) -> Result<VideoEncoder> {
+    for encoder in encoders {
+        if let Some(codec) = encoder.codec() {
+            if !output_type.supports(codec) {
+                eprintln!("Can't use the {} encoder: {:?} video can't be sent in {:?}", encoder.codec_name(), codec, output_type);
+                continue;
             }
         }
+        match probe_encoder(encoder, output_type).await {
+            Ok(()) => {
+                println!("Using the {} encoder", encoder.codec_name());
+                return Ok(encoder.clone());
+            }
+            Err(e) => {
+                eprintln!("Can't use the {} encoder: {e}", encoder.codec_name());
+            }
+        }
+    }
+
+    Err(anyhow!("None of the configured encoders work"))
+}
+
+fn get_vaapi_renderer() -> anyhow::Result<String> {
+    let renderer_path = PathBuf::from("/dev/dri/");
+    for dir_entry_result in renderer_path.read_dir()? {
+        let Ok(dir_entry) = dir_entry_result else {
+            continue;
+        };
+        let dev_name = String::from(dir_entry.file_name().to_str().unwrap_or(""));
+        if dev_name.contains("render") {
+            return Ok(String::from(dir_entry.path().to_str().unwrap()));
+        }
+    }
+
+    Err(anyhow!("Renderer not found"))
+}
+
+impl FFmpeg {
+    pub fn new() -> Self {
+        Self {
+            process: None,
+            progress: broadcast::channel(PROGRESS_CHANNEL_SIZE).0,
+            fatal_errors: None
+        }
+    }
+
+    ///
+    /// Subscribes to the progress reports from now on. Every process
+    /// started by this FFmpeg ends its reports with `ProgressEvent::Ended`.
+    /// A subscriber that falls more than `PROGRESS_CHANNEL_SIZE` events
+    /// behind gets `RecvError::Lagged` with the number it missed.
+    /// 
+    pub fn progress(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.progress.subscribe()
+    }
 
-        // Output path
-        combined_args.push(&output.path);
+    pub fn start(&mut self, mut command: FFmpegCommand) -> Result<()> {
+        if command.uses_vaapi() && command.vaapi_device.is_none() {
+            command.vaapi_device = Some(get_vaapi_renderer()?);
+        }
+        let args = command.to_args()?;
 
-        println!("ARGS {:?}", combined_args);
+        println!("ARGS {:?}", args);
 
         // Start FFmpeg process
-        let child_handle = Command::new("ffmpeg")
-        .args(combined_args)
+        let mut child_handle = Command::new("ffmpeg")
+        .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        .kill_on_drop(true)
         .spawn()?;
 
+        let (fatal_tx, fatal_rx) = mpsc::unbounded_channel();
+        if let Some(stderr) = child_handle.stderr.take() {
+            spawn(read_stderr(stderr, self.progress.clone(), fatal_tx));
+        }
+        self.fatal_errors = Some(fatal_rx);
         self.process = Some(child_handle);
 
         Ok(())
@@ -199,9 +1141,12 @@ impl FFmpeg {
 
         let mut buffer = [0u8; CHUNK_SIZE];
 
-        stdout.read_buf(&mut buffer.as_mut_slice()).await?;
+        let bytes_read = stdout.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            return Err(anyhow!("FFmpeg closed its output"));
+        }
 
-        return Ok(Vec::from(buffer));
+        Ok(Vec::from(&buffer[..bytes_read]))
     }
 
     pub async fn write(&mut self, buffer: Vec<u8>) -> Result<()> {
@@ -216,32 +1161,329 @@ impl FFmpeg {
         let mut cursor = Cursor::new(buffer);
         stdin.write_all_buf(&mut cursor).await?;
 
-        return Ok(());
+        Ok(())
+    }
+
+    /// Takes over FFmpeg's input, e.g. to feed it from another task
+    pub fn take_stdin(&mut self) -> Option<ChildStdin> {
+        self.process.as_mut()?.stdin.take()
     }
 
+    /// A fatal error FFmpeg reported, if any
+    pub fn fatal_error(&mut self) -> Option<String> {
+        self.fatal_errors.as_mut()?.try_recv().ok()
+    }
+
+    ///
+    /// Asks FFmpeg to finish by closing its input and sending it SIGINT,
+    /// the output can still be read until it exits
+    /// 
+    pub fn interrupt(&mut self) {
+        let Some(process) = &mut self.process else {
+            return;
+        };
+        drop(process.stdin.take());
+        if let Some(pid) = process.id() {
+            // SAFETY: kill doesn't touch memory, the pid belongs to our not yet reaped child
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGINT);
+            }
+        }
+    }
+
+    ///
+    /// Lets FFmpeg finish the output (like the FLV trailer) by closing
+    /// its input and interrupting it. It's killed if it doesn't exit
+    /// within `grace_period`.
+    /// 
+    pub async fn stop_gracefully(&mut self, grace_period: Duration) -> anyhow::Result<()> {
+        self.interrupt();
+        let Some(mut process) = self.process.take() else {
+            return Ok(());
+        };
+
+        if timeout(grace_period, process.wait()).await.is_err() {
+            eprintln!("FFmpeg didn't stop in time, killing it");
+            process.kill().await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn stop(&mut self) -> anyhow::Result<()> {
+        let Some(mut process) = self.process.take() else {
+            return Ok(());
+        };
+        process.kill().await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Waits for FFmpeg to exit. If it reports an error it can't
+    /// recover from, it's killed and the error is returned.
+    /// 
     pub async fn wait_until_end(&mut self) -> anyhow::Result<ExitStatus> {
         let Some(process) = &mut self.process else {
             return Err(anyhow!("No process"));
         };
-        let mut stderr = process.stderr.take().unwrap();
-        
-        let (stderr_tx, stderr_rx) = oneshot::channel::<()>();
-
-        spawn(async move {
-            let mut a = [0u8; 1];
-            let _ = stderr.read(&mut a).await;
-            let _ = stderr_tx.send(());
-        });
+        let Some(fatal_errors) = &mut self.fatal_errors else {
+            return Err(anyhow!("No stderr"));
+        };
 
         select! {
             exit_status = process.wait() => {
                 println!("Process exited");
-                return Ok(exit_status?);
+                Ok(exit_status?)
             }
-            _ = stderr_rx => {
-                println!("Stderr outputted, exiting...");
-                return Ok(ExitStatus::default());
+            Some(line) = fatal_errors.recv() => {
+                // Also reaps the process
+                let _ = process.kill().await;
+                self.process = None;
+                Err(anyhow!(line))
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(args: &[String], arg: &str) -> usize {
+        args.iter().position(|a| a == arg).unwrap_or_else(|| panic!("{arg} missing from {args:?}"))
+    }
+
+    #[test]
+    fn input_options_come_before_their_input() {
+        let args = FFmpegCommand::new()
+            .input(Input::new("/dev/video0", InputType::V4L2).framerate(30).video_size(1920, 1080).thread_queue_size(512))
+            .input(Input::new("default", InputType::PulseAudio))
+            .video_encoder(VideoEncoder::SoftwareH264(X264Settings::default()))
+            .audio_encoder(AudioEncoder::AAC)
+            .output(Output::new("-", OutputType::FLV))
+            .to_args()
+            .unwrap();
+
+        let camera = position(&args, "/dev/video0");
+        assert_eq!(args[camera - 1], "-i");
+        for option in ["-framerate", "-video_size", "-thread_queue_size"] {
+            assert!(position(&args, option) < camera, "{option} is after the camera input");
+        }
+        assert_eq!(args[position(&args, "default") - 1], "-i");
+        assert!(position(&args, "pulse") > camera);
+        assert_eq!(args.last().map(String::as_str), Some("-"));
+    }
+
+    #[test]
+    fn vaapi_uploads_frames_after_the_device_is_set() {
+        let args = FFmpegCommand::new()
+            .input(Input::new("/dev/video0", InputType::V4L2))
+            .video_filter("yadif")
+            .video_encoder(VideoEncoder::VAAPIH264(VaapiSettings::default()))
+            .video_format(VideoFormat { width: Some(1280), ..Default::default() })
+            .audio_encoder(AudioEncoder::Disabled)
+            .output(Output::new("-", OutputType::FLV))
+            .vaapi_device("/dev/dri/renderD128")
+            .to_args()
+            .unwrap();
+
+        let device = position(&args, "-vaapi_device");
+        assert_eq!(args[device + 1], "/dev/dri/renderD128");
+        assert!(device < position(&args, "-i"));
+        let filters = &args[position(&args, "-vf") + 1];
+        assert_eq!(filters, "yadif,format=nv12,hwupload,scale_vaapi=w=1280:h=-2");
+        assert_eq!(args[position(&args, "-c:v") + 1], "h264_vaapi");
+        assert!(args.contains(&"-an".to_string()));
+    }
+
+    #[test]
+    fn raw_audio_has_a_fixed_format() {
+        let args = FFmpegCommand::new()
+            .input(Input::new("default", InputType::PulseAudio))
+            .video_encoder(VideoEncoder::Copy)
+            .audio_encoder(AudioEncoder::PCM)
+            .audio_settings(AudioSettings { bitrate: Some(128), sample_rate: Some(44100), channels: Some(1) })
+            .output(Output::new("-", OutputType::PCM))
+            .to_args()
+            .unwrap();
+        let output_args = &args[position(&args, "-c:a")..];
+        assert_eq!(output_args, ["-c:a", "pcm_s16le", "-ar", "48000", "-ac", "2", "-f", "s16le", "-"]);
+
+        let args = FFmpegCommand::new()
+            .input(Input::new("pipe:0", InputType::PCM))
+            .video_encoder(VideoEncoder::Copy)
+            .output(Output::new("-", OutputType::FLV))
+            .to_args()
+            .unwrap();
+        let input = position(&args, "pipe:0");
+        assert_eq!(&args[input - 7..input], ["-f", "s16le", "-ar", "48000", "-ac", "2", "-i"]);
+    }
+
+    #[test]
+    fn vaapi_needs_a_device() {
+        let result = FFmpegCommand::new()
+            .video_encoder(VideoEncoder::VAAPIH264(VaapiSettings::default()))
+            .output(Output::new("-", OutputType::FLV))
+            .to_args();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn copying_video_rejects_filters() {
+        let result = FFmpegCommand::new()
+            .input(Input::new("pipe:0", InputType::FLV))
+            .video_filter("yadif")
+            .video_encoder(VideoEncoder::Copy)
+            .audio_encoder(AudioEncoder::Copy)
+            .output(Output::new("-", OutputType::FLV))
+            .to_args();
+        assert_eq!(result.unwrap_err().to_string(), "Video filters can't be used when copying the video");
+    }
+
+    #[test]
+    fn copying_audio_rejects_filters() {
+        let result = FFmpegCommand::new()
+            .video_encoder(VideoEncoder::Copy)
+            .audio_filter("loudnorm")
+            .audio_encoder(AudioEncoder::Copy)
+            .output(Output::new("-", OutputType::FLV))
+            .to_args();
+        assert_eq!(result.unwrap_err().to_string(), "Audio filters can't be used without encoding the audio");
+    }
+
+    #[test]
+    fn opus_is_rejected_in_flv() {
+        let command = FFmpegCommand::new()
+            .video_encoder(VideoEncoder::Copy)
+            .audio_encoder(AudioEncoder::Opus);
+        let result = command.output(Output::new("-", OutputType::FLV)).to_args();
+        assert_eq!(result.unwrap_err().to_string(), "Opus audio can't be sent in FLV");
+
+        let args = FFmpegCommand::new()
+            .video_encoder(VideoEncoder::Copy)
+            .audio_encoder(AudioEncoder::Opus)
+            .output(Output::new("srt://localhost:9000", OutputType::MPEGTS))
+            .to_args()
+            .unwrap();
+        assert_eq!(args[position(&args, "-ar") + 1], "48000");
+    }
+
+    #[test]
+    fn parses_progress_reports() {
+        let mut progress = Progress::default();
+        let report = [
+            "frame=120",
+            "fps=29.97",
+            "bitrate=3998.2kbits/s",
+            "total_size=2000000",
+            "out_time_us=4000000",
+            "dup_frames=1",
+            "drop_frames=2",
+            "speed=1.01x"
+        ];
+        for line in report {
+            assert!(matches!(parse_progress_line(&mut progress, line), ProgressLine::Field));
+        }
+        assert!(matches!(parse_progress_line(&mut progress, "progress=continue"), ProgressLine::End));
+
+        assert_eq!(progress.frame, 120);
+        assert_eq!(progress.bitrate, Some(3998.2));
+        assert_eq!(progress.out_time, Duration::from_secs(4));
+        assert_eq!((progress.dup_frames, progress.drop_frames), (1, 2));
+        assert_eq!(progress.speed, Some(1.01));
+        assert!(!progress.finished);
+
+        assert!(matches!(parse_progress_line(&mut progress, "bitrate=N/A"), ProgressLine::Field));
+        assert_eq!(progress.bitrate, None);
+        assert!(matches!(parse_progress_line(&mut progress, "progress=end"), ProgressLine::End));
+        assert!(progress.finished);
+    }
+
+    #[tokio::test]
+    async fn progress_events_end_with_the_process() {
+        let (progress_tx, mut progress_rx) = broadcast::channel(PROGRESS_CHANNEL_SIZE);
+        let (fatal_tx, mut fatal_rx) = mpsc::unbounded_channel();
+        let stderr: &[u8] = b"frame=1\nprogress=continue\n[fatal] Conversion failed!\nframe=2\nprogress=end\n";
+        read_stderr(stderr, progress_tx, fatal_tx).await;
+
+        let frames: Vec<u64> = (0..2).map(|_| match progress_rx.try_recv() {
+            Ok(ProgressEvent::Report(report)) => report.frame,
+            event => panic!("Expected a report, got {event:?}")
+        }).collect();
+        assert_eq!(frames, [1, 2]);
+        assert!(matches!(progress_rx.try_recv(), Ok(ProgressEvent::Ended)));
+        assert_eq!(fatal_rx.try_recv().unwrap(), "[fatal] Conversion failed!");
+    }
+
+    #[test]
+    fn log_lines_are_not_progress() {
+        let mut progress = Progress::default();
+        let line = "[flv @ 0x55d0] [warning] Failed to update header with correct duration.";
+        assert!(matches!(parse_progress_line(&mut progress, line), ProgressLine::Other));
+        let line = "[h264_vaapi @ 0x55d0] [error] Encode failed: key=value";
+        assert!(matches!(parse_progress_line(&mut progress, line), ProgressLine::Other));
+    }
+
+    #[test]
+    fn checks_codecs_against_the_container() {
+        assert!(OutputType::FLV.supports(VideoCodec::H264));
+        assert!(OutputType::FLV.supports(VideoCodec::AV1));
+        assert!(OutputType::FLV.supports_enhanced(VideoCodec::HEVC));
+        assert!(!OutputType::FLV.supports_enhanced(VideoCodec::H264));
+        assert!(!OutputType::MPEGTS.supports(VideoCodec::VP9));
+
+        let result = FFmpegCommand::new()
+            .video_encoder(VideoEncoder::SoftwareAV1(SvtAv1Settings::default()))
+            .output(Output::new("srt://localhost:9000", OutputType::MPEGTS))
+            .to_args();
+        assert_eq!(result.unwrap_err().to_string(), "AV1 video can't be sent in MPEGTS");
+    }
+
+    #[test]
+    fn rejects_unknown_encoder_names() {
+        let settings = EncoderSettings::default();
+        let chain = encoder_chain(&["hevc".into(), "X264".into()], &settings).unwrap();
+        assert_eq!(chain.iter().map(VideoEncoder::codec_name).collect::<Vec<_>>(), ["hevc_vaapi", "libx264"]);
+        assert!(encoder_chain(&["H264".into(), "NVENC".into()], &settings).is_err());
+        assert!(encoder_chain(&[], &settings).is_err());
+    }
+
+    #[test]
+    fn tells_encoder_errors_from_output_errors() {
+        let encoder = VideoEncoder::VAAPIH264(VaapiSettings::default());
+        assert!(encoder.caused("[h264_vaapi @ 0x55d0] [error] Failed to end picture encode issue: 23"));
+        assert!(encoder.caused("[AVHWDeviceContext @ 0x55d0] [error] Failed to initialise VAAPI connection: -1"));
+        assert!(encoder.caused("Renderer not found"));
+        assert!(!encoder.caused("[rtmp @ 0x55d0] [error] RTMP_SendPacket, failed to send packet"));
+        assert!(!encoder.caused("[flv @ 0x55d0] [error] Failed to update header: Broken pipe"));
+        assert!(!encoder.caused("[vost#0:0/h264_vaapi @ 0x55d0] [error] Error submitting a packet to the muxer: Connection reset by peer"));
+        assert!(encoder.caused("[vost#0:0/h264_vaapi @ 0x55d0] [error] Error while opening encoder - maybe incorrect parameters"));
+
+        let software = VideoEncoder::SoftwareH264(X264Settings::default());
+        assert!(!software.caused("[AVHWDeviceContext @ 0x55d0] [error] Failed to initialise VAAPI connection: -1"));
+    }
+
+    #[test]
+    fn classifies_stderr_lines() {
+        let warnings = [
+            "[flv @ 0x55d0] [warning] Failed to update header with correct duration.",
+            "[aac @ 0x55d0] [warning] Queue input is backward in time"
+        ];
+        for line in warnings {
+            assert_eq!(classify_stderr_line(line), StderrSeverity::Warning, "{line}");
+        }
+
+        let fatal = [
+            "[fatal] Error opening output files: Invalid argument",
+            "[flv @ 0x55d0] [error] Failed to update header: Broken pipe",
+            "[video4linux2,v4l2 @ 0x55d0] [error] ioctl(VIDIOC_STREAMON): No such device",
+            "[rtmp @ 0x55d0] [error] RTMP_ReadPacket, failed to read RTMP packet header",
+            "[vost#0:0/h264_vaapi @ 0x55d0] [error] Error submitting a packet to the muxer: Connection reset by peer",
+            "[vost#0:0/h264_vaapi @ 0x55d0] [error] Error while opening encoder - maybe incorrect parameters"
+        ];
+        for line in fatal {
+            assert_eq!(classify_stderr_line(line), StderrSeverity::Fatal, "{line}");
+        }
+    }
+}
@@ -24,7 +24,7 @@ pub async fn get_camera(pat: Option<&str>) -> anyhow::Result<String> {
         }
     }
 
-    if matching_devices.len() == 0 {
+    if matching_devices.is_empty() {
         return Err(anyhow!("Not found"));
     }
 
@@ -0,0 +1,369 @@
+use std::ops::Range;
+
+/// "FLV", version, flags and the header size
+const FILE_HEADER_SIZE: usize = 9;
+/// Type (1) + data size (3) + timestamp (4) + stream id (3)
+const TAG_HEADER_SIZE: usize = 11;
+/// Every tag is followed by its own size
+const TAG_TRAILER_SIZE: usize = 4;
+/// Bigger tags aren't waited for while resynchronizing, random bytes
+/// that happen to look like a tag header could stall the stream otherwise
+const MAX_RESYNC_TAG_SIZE: usize = 2 * 1024 * 1024;
+
+const AUDIO_TAG: u8 = 8;
+const VIDEO_TAG: u8 = 9;
+const SCRIPT_TAG: u8 = 18;
+
+/// Legacy video codec ids that carry a sequence header
+const AVC_CODEC_ID: u8 = 7;
+const HEVC_CODEC_ID: u8 = 12;
+const AAC_SOUND_FORMAT: u8 = 10;
+/// Sound format used by enhanced FLV for the codecs it added
+const EX_HEADER_SOUND_FORMAT: u8 = 9;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TagKind {
+    Audio,
+    Video,
+    Script
+}
+
+///
+/// A complete FLV tag, including its header and the trailing tag size,
+/// so it can be written out again as is
+/// 
+#[derive(Clone)]
+pub struct FlvTag {
+    pub kind: TagKind,
+    pub bytes: Vec<u8>
+}
+
+impl FlvTag {
+    fn data(&self) -> &[u8] {
+        &self.bytes[TAG_HEADER_SIZE..self.bytes.len() - TAG_TRAILER_SIZE]
+    }
+
+    /// A video frame the decoder can start from
+    pub fn is_keyframe(&self) -> bool {
+        let Some(&first) = self.data().first() else {
+            return false;
+        };
+        self.kind == TagKind::Video && (first >> 4) & 0x07 == 1 && !self.is_config()
+    }
+
+    ///
+    /// A sequence header (e.g. the AVC decoder configuration), which
+    /// the decoder needs before any frame
+    /// 
+    pub fn is_config(&self) -> bool {
+        let data = self.data();
+        let Some(&first) = data.first() else {
+            return false;
+        };
+        match self.kind {
+            TagKind::Video if first & 0x80 != 0 => first & 0x0F == 0,
+            TagKind::Video => matches!(first & 0x0F, AVC_CODEC_ID | HEVC_CODEC_ID) && data.get(1) == Some(&0),
+            TagKind::Audio => match first >> 4 {
+                AAC_SOUND_FORMAT => data.get(1) == Some(&0),
+                EX_HEADER_SOUND_FORMAT => first & 0x0F == 0,
+                _ => false
+            },
+            TagKind::Script => false
+        }
+    }
+
+    ///
+    /// Identifies the codec of a video tag, the codec id for legacy
+    /// FLV and the FourCC for enhanced FLV
+    /// 
+    pub fn video_codec(&self) -> Option<u32> {
+        let data = self.data();
+        let first = *data.first()?;
+        if self.kind != TagKind::Video {
+            None
+        } else if first & 0x80 != 0 {
+            Some(u32::from_be_bytes(data.get(1..5)?.try_into().ok()?))
+        } else {
+            Some((first & 0x0F) as u32)
+        }
+    }
+}
+
+enum ReaderState {
+    /// Waiting for the file header at the start of a stream
+    Header,
+    Tags,
+    /// Looking for the next tag after data was lost or corrupted
+    Resync
+}
+
+///
+/// Splits an FLV byte stream into tags and remembers what a decoder
+/// needs to join the stream: the file header, the metadata and the
+/// latest audio and video sequence headers. Lost data is skipped by
+/// searching for the next well formed tag.
+/// 
+pub struct FlvReader {
+    buffer: Vec<u8>,
+    state: ReaderState,
+    /// Set when data had to be skipped, cleared by `take_discontinuity`
+    discontinuity: bool,
+    file_header: Vec<u8>,
+    metadata: Option<FlvTag>,
+    video_config: Option<FlvTag>,
+    audio_config: Option<FlvTag>
+}
+
+impl FlvReader {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            state: ReaderState::Header,
+            discontinuity: false,
+            file_header: Vec::new(),
+            metadata: None,
+            video_config: None,
+            audio_config: None
+        }
+    }
+
+    /// Adds stream data and returns the tags that are now complete
+    pub fn push(&mut self, data: &[u8]) -> Vec<FlvTag> {
+        self.buffer.extend_from_slice(data);
+
+        let mut tags = vec![];
+        loop {
+            match self.state {
+                ReaderState::Header => {
+                    if self.buffer.len() < FILE_HEADER_SIZE {
+                        break;
+                    }
+                    if !self.buffer.starts_with(b"FLV") {
+                        self.lost_sync();
+                        continue;
+                    }
+                    let data_offset = u32::from_be_bytes(self.buffer[5..9].try_into().unwrap()) as usize;
+                    let header_size = data_offset.max(FILE_HEADER_SIZE) + TAG_TRAILER_SIZE;
+                    if self.buffer.len() < header_size {
+                        break;
+                    }
+                    self.file_header = self.buffer.drain(..header_size).collect();
+                    self.state = ReaderState::Tags;
+                }
+                ReaderState::Tags => match tag_at(&self.buffer, 0) {
+                    TagCheck::Complete(range) => {
+                        let bytes: Vec<u8> = self.buffer.drain(range).collect();
+                        tags.push(self.remember(bytes));
+                    }
+                    TagCheck::Incomplete(_) => break,
+                    TagCheck::Invalid => self.lost_sync()
+                },
+                ReaderState::Resync => {
+                    let mut found = None;
+                    for offset in 0..self.buffer.len() {
+                        match tag_at(&self.buffer, offset) {
+                            TagCheck::Incomplete(size) if size > MAX_RESYNC_TAG_SIZE => continue,
+                            TagCheck::Invalid => continue,
+                            check => {
+                                found = Some((offset, check));
+                                break;
+                            }
+                        }
+                    }
+                    // Positions close to the end can't be ruled out yet, so only an empty buffer finds nothing
+                    let Some((offset, check)) = found else {
+                        break;
+                    };
+                    self.buffer.drain(..offset);
+                    match check {
+                        TagCheck::Complete(_) => self.state = ReaderState::Tags,
+                        _ => break
+                    }
+                }
+            }
+        }
+
+        tags
+    }
+
+    /// The next data starts a new stream with its own file header
+    pub fn start_stream(&mut self) {
+        if !self.buffer.is_empty() {
+            self.discontinuity = true;
+        }
+        self.buffer.clear();
+        self.state = ReaderState::Header;
+    }
+
+    /// Returns true once after data had to be skipped
+    pub fn take_discontinuity(&mut self) -> bool {
+        std::mem::take(&mut self.discontinuity)
+    }
+
+    pub fn has_video(&self) -> bool {
+        self.video_config.is_some()
+    }
+
+    /// Codec of the latest video sequence header
+    pub fn video_codec(&self) -> Option<u32> {
+        self.video_config.as_ref().and_then(FlvTag::video_codec)
+    }
+
+    ///
+    /// Everything a new decoder has to receive before it can join the
+    /// stream at a keyframe
+    /// 
+    pub fn stream_header(&self) -> Vec<u8> {
+        let mut header = self.file_header.clone();
+        for tag in [&self.metadata, &self.video_config, &self.audio_config].into_iter().flatten() {
+            header.extend_from_slice(&tag.bytes);
+        }
+
+        header
+    }
+
+    fn lost_sync(&mut self) {
+        // Skip at least one byte, so the same position isn't found again
+        self.buffer.drain(..1.min(self.buffer.len()));
+        self.discontinuity = true;
+        self.state = ReaderState::Resync;
+    }
+
+    fn remember(&mut self, bytes: Vec<u8>) -> FlvTag {
+        let kind = match bytes[0] & 0x1F {
+            AUDIO_TAG => TagKind::Audio,
+            VIDEO_TAG => TagKind::Video,
+            _ => TagKind::Script
+        };
+        let tag = FlvTag { kind, bytes };
+        if tag.kind == TagKind::Script {
+            self.metadata = Some(tag.clone());
+        } else if tag.is_config() && tag.kind == TagKind::Video {
+            self.video_config = Some(tag.clone());
+        } else if tag.is_config() {
+            self.audio_config = Some(tag.clone());
+        }
+
+        tag
+    }
+}
+
+enum TagCheck {
+    Complete(Range<usize>),
+    /// Looks like a tag, but not all of it arrived yet. Holds the
+    /// announced data size, if the tag header is complete.
+    Incomplete(usize),
+    Invalid
+}
+
+fn tag_at(buffer: &[u8], offset: usize) -> TagCheck {
+    let Some(header) = buffer.get(offset..offset + TAG_HEADER_SIZE) else {
+        return TagCheck::Incomplete(0);
+    };
+    let tag_type = header[0] & 0x1F;
+    // The stream id is always 0
+    if !matches!(tag_type, AUDIO_TAG | VIDEO_TAG | SCRIPT_TAG) || header[8..11] != [0, 0, 0] {
+        return TagCheck::Invalid;
+    }
+
+    let data_size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+    let tag_size = TAG_HEADER_SIZE + data_size;
+    let Some(trailer) = buffer.get(offset + tag_size..offset + tag_size + TAG_TRAILER_SIZE) else {
+        return TagCheck::Incomplete(data_size);
+    };
+    if u32::from_be_bytes(trailer.try_into().unwrap()) as usize != tag_size {
+        return TagCheck::Invalid;
+    }
+
+    TagCheck::Complete(offset..offset + tag_size + TAG_TRAILER_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE_HEADER: [u8; 13] = [b'F', b'L', b'V', 1, 5, 0, 0, 0, 9, 0, 0, 0, 0];
+
+    fn tag(tag_type: u8, data: &[u8]) -> Vec<u8> {
+        let size = data.len() as u32;
+        let mut bytes = vec![tag_type];
+        bytes.extend_from_slice(&size.to_be_bytes()[1..]);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(data);
+        bytes.extend_from_slice(&(TAG_HEADER_SIZE as u32 + size).to_be_bytes());
+        bytes
+    }
+
+    fn avc_config() -> Vec<u8> {
+        tag(VIDEO_TAG, &[0x17, 0, 0, 0, 0, 1, 2, 3])
+    }
+
+    fn avc_keyframe() -> Vec<u8> {
+        tag(VIDEO_TAG, &[0x17, 1, 0, 0, 0, 4, 5, 6])
+    }
+
+    fn avc_frame() -> Vec<u8> {
+        tag(VIDEO_TAG, &[0x27, 1, 0, 0, 0, 7, 8])
+    }
+
+    #[test]
+    fn reads_tags_split_across_pushes() {
+        let stream = [FILE_HEADER.to_vec(), tag(SCRIPT_TAG, b"meta"), avc_config(), avc_keyframe()].concat();
+        let mut reader = FlvReader::new();
+        let mut tags = vec![];
+        for byte in &stream {
+            tags.extend(reader.push(&[*byte]));
+        }
+
+        let kinds: Vec<TagKind> = tags.iter().map(|tag| tag.kind).collect();
+        assert_eq!(kinds, [TagKind::Script, TagKind::Video, TagKind::Video]);
+        assert!(tags[1].is_config() && !tags[1].is_keyframe());
+        assert!(tags[2].is_keyframe());
+        assert_eq!(reader.video_codec(), Some(AVC_CODEC_ID as u32));
+        assert!(!reader.take_discontinuity());
+    }
+
+    #[test]
+    fn stream_header_holds_the_configuration() {
+        let mut reader = FlvReader::new();
+        reader.push(&[FILE_HEADER.to_vec(), tag(SCRIPT_TAG, b"meta"), avc_config(), avc_keyframe(), avc_frame()].concat());
+
+        let expected = [FILE_HEADER.to_vec(), tag(SCRIPT_TAG, b"meta"), avc_config()].concat();
+        assert_eq!(reader.stream_header(), expected);
+    }
+
+    #[test]
+    fn resyncs_after_lost_data() {
+        let mut reader = FlvReader::new();
+        reader.push(&[FILE_HEADER.to_vec(), avc_config()].concat());
+
+        let frame = avc_frame();
+        let tags = reader.push(&[&frame[..7], &avc_keyframe()[..]].concat());
+        assert_eq!(tags.len(), 1);
+        assert!(tags[0].is_keyframe());
+        assert!(reader.take_discontinuity());
+    }
+
+    #[test]
+    fn new_stream_starts_with_a_file_header() {
+        let mut reader = FlvReader::new();
+        reader.push(&[FILE_HEADER.to_vec(), avc_config(), avc_keyframe()].concat());
+
+        reader.start_stream();
+        let tags = reader.push(&[FILE_HEADER.to_vec(), avc_keyframe()].concat());
+        assert_eq!(tags.len(), 1);
+        assert!(!reader.take_discontinuity());
+    }
+
+    #[test]
+    fn reads_enhanced_flv_video() {
+        let mut reader = FlvReader::new();
+        let hevc_config = tag(VIDEO_TAG, &[0x90, b'h', b'v', b'c', b'1', 1, 2]);
+        let hevc_keyframe = tag(VIDEO_TAG, &[0x91, b'h', b'v', b'c', b'1', 3, 4]);
+        let tags = reader.push(&[FILE_HEADER.to_vec(), hevc_config, hevc_keyframe].concat());
+
+        assert!(tags[0].is_config());
+        assert!(tags[1].is_keyframe());
+        assert_eq!(reader.video_codec(), Some(u32::from_be_bytes(*b"hvc1")));
+    }
+}
@@ -1,25 +1,62 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use serde::Deserialize;
 use anyhow::anyhow;
-use srvsession::{introduce_connection, IntroductionResult, ServerSession};
-use tokio::{fs::read_to_string, net::TcpListener, sync::Mutex};
-use crate::connection::{Connection, ConnectionPacket, PacketType};
-use crate::session::Session;
+use srvsession::{introduce_connection, reject_connection, IntroductionResult, ServerSession};
+use supervisor::Shutdown;
+use tokio::{fs::read_to_string, net::TcpListener, select, spawn, sync::Mutex, time::interval};
+use tokio_rustls::TlsAcceptor;
+use crate::connection::{Connection, ConnectionPacket, PacketType, RejectReason};
+use crate::session::{heartbeat_config, HeartbeatConfig};
+use crate::tls::load_acceptor;
 
+// Shared between the binaries, each one only uses part of them
+#[path ="../auth.rs"]
+#[allow(dead_code)]
+mod auth;
 #[path ="../connection.rs"]
+#[allow(dead_code)]
 mod connection;
 #[path ="../ffmpeg.rs"]
+#[allow(dead_code)]
 mod ffmpeg;
 #[path ="../session.rs"]
+#[allow(dead_code)]
 mod session;
+#[path ="../supervisor.rs"]
+#[allow(dead_code)]
+mod supervisor;
+#[path ="../tls.rs"]
+#[allow(dead_code)]
+mod tls;
+mod flv;
+mod reorder;
 mod srvsession;
 
 const ALLVU_PORT: u16 = 1312;
 const ALLVU_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// How long a session without connections can still be joined
+const SESSION_EXPIRY: Duration = Duration::from_secs(60);
+
+type Sessions = Arc<Mutex<Vec<Arc<Mutex<ServerSession>>>>>;
 
 #[derive(Deserialize)]
 struct Config {
-    rtmp_output: String
+    rtmp_output: String,
+    /// Shared secret clients have to prove they know
+    password: Option<String>,
+    /// PEM certificate chain, enables and requires TLS together with `tls_key`
+    tls_certificate: Option<String>,
+    /// PEM private key of the certificate
+    tls_key: Option<String>,
+    /// How long the server waits for a missing video chunk, in milliseconds
+    max_latency: Option<u64>,
+    /// Time between heartbeats on every connection, in milliseconds
+    heartbeat_interval: Option<u64>,
+    /// Unanswered heartbeats after which a connection is dropped
+    max_missed_heartbeats: Option<u32>,
+    /// Built from the heartbeat options when the config is loaded
+    #[serde(skip)]
+    heartbeat: HeartbeatConfig
 }
 
 async fn get_config() -> anyhow::Result<Config> {
@@ -29,43 +66,133 @@ async fn get_config() -> anyhow::Result<Config> {
     }
 
     let contents = read_to_string(config_path).await?;
-    let config_file: Config = toml::from_str(&contents)?;
+    let mut config_file: Config = toml::from_str(&contents)?;
+    config_file.heartbeat = heartbeat_config(config_file.heartbeat_interval, config_file.max_missed_heartbeats)?;
     Ok(config_file)
 }
 
+async fn handle_connection(
+    connection: Connection,
+    sessions: Sessions,
+    config: Arc<Config>,
+    tls_acceptor: Option<TlsAcceptor>
+) -> anyhow::Result<()> {
+    let password = config.password.as_deref().unwrap_or_default();
+    let (mut connection, introduction_result) = introduce_connection(connection, password, tls_acceptor.as_ref()).await?;
+    match introduction_result {
+        IntroductionResult::NewSession => {
+            println!("Creating new session...");
+            let max_latency = Duration::from_millis(config.max_latency.unwrap_or(500));
+            let new_session = Arc::new(Mutex::new({
+                let mut session = ServerSession::new(max_latency, config.heartbeat, config.rtmp_output.clone());
+                let token = session.retreive_token();
+                connection.write(ConnectionPacket { 
+                    packet_type: PacketType::NewSession, 
+                    flags: 0,
+                    packet_data: token.as_bytes().to_vec()
+                }).await?;
+                session.add_connection(connection);
+                session
+            }));
+            sessions.lock().await.push(new_session.clone());
+        }
+        IntroductionResult::ExistingSession(token) => {
+            println!("Connecting client to existing session");
+            let sessions_lock = sessions.lock().await;
+            let mut matching_session = None;
+            for session in sessions_lock.iter() {
+                let mut session_lock = session.lock().await;
+                if session_lock.retreive_token() == token && !session_lock.is_expired(SESSION_EXPIRY) {
+                    matching_session = Some(session.clone());
+                    break;
+                }
+            }
+            drop(sessions_lock);
+
+            let Some(session) = matching_session else {
+                reject_connection(&mut connection, RejectReason::UnknownSession, "Unknown or expired session token").await?;
+                return Err(anyhow!("Client sent an unknown or expired session token"));
+            };
+            connection.write(ConnectionPacket {
+                packet_type: PacketType::ExistingSession,
+                flags: 0,
+                packet_data: token.as_bytes().to_vec()
+            }).await?;
+            session.lock().await.add_connection(connection);
+        }
+    }
+
+    Ok(())
+}
+
+async fn remove_expired_sessions(sessions: &Sessions) {
+    let mut sessions_lock = sessions.lock().await;
+    let mut active_sessions = Vec::with_capacity(sessions_lock.len());
+    for session in sessions_lock.drain(..) {
+        if !session.lock().await.is_expired(SESSION_EXPIRY) {
+            active_sessions.push(session);
+        }
+    }
+    *sessions_lock = active_sessions;
+}
+
+/// Stops every session, so FFmpeg can finish the RTMP stream
+async fn stop_sessions(sessions: &Sessions) {
+    let stopped_sessions = std::mem::take(&mut *sessions.lock().await);
+    for session in stopped_sessions {
+        session.lock().await.stop().await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     println!("Server mode");
+    let config = Arc::new(get_config().await?);
+    if config.password.is_none() {
+        eprintln!("No password configured, any AllVu client can connect");
+    }
+    let tls_acceptor = match (&config.tls_certificate, &config.tls_key) {
+        (Some(certificate_path), Some(key_path)) => Some(load_acceptor(certificate_path, key_path)?),
+        (None, None) => {
+            eprintln!("No TLS certificate configured, connections are not encrypted");
+            None
+        }
+        _ => {
+            return Err(anyhow!("Both tls_certificate and tls_key have to be configured"));
+        }
+    };
     let listener = TcpListener::bind(format!("0.0.0.0:{ALLVU_PORT}")).await?;
 
-    //let mut session: Session = Session::new();
-    let mut sessions: Vec<Arc<Mutex<ServerSession>>> = Vec::new();
+    let sessions: Sessions = Arc::new(Mutex::new(Vec::new()));
+    let mut shutdown = Shutdown::listen()?;
+
+    // Expired sessions are dropped, which also stops their FFmpeg
+    let expiry_sessions = sessions.clone();
+    spawn(async move {
+        let mut expiry_interval = interval(SESSION_EXPIRY / 4);
+        loop {
+            expiry_interval.tick().await;
+            remove_expired_sessions(&expiry_sessions).await;
+        }
+    });
     
     loop {
-        let (tcp_stream, _) = listener.accept().await?;
-        
-        let mut connection = Connection::new(tcp_stream);
-        let introduction_result = introduce_connection(&mut connection).await?;
-        match introduction_result {
-            IntroductionResult::NewSession(password) => {
-                println!("Creating new session...");
-                let new_session = Arc::new(Mutex::new({
-                    let mut session = ServerSession::new();
-                    let token = session.retreive_token();
-                    connection.write(ConnectionPacket { 
-                        packet_type: 2, 
-                        packet_data: token.as_bytes().to_vec()
-                    }).await?;
-                    session.add_connection(connection);
-                    session
-                }));
-                sessions.push(new_session.clone());
+        let (tcp_stream, address) = select! {
+            accept_result = listener.accept() => accept_result?,
+            _ = shutdown.wait() => {
+                stop_sessions(&sessions).await;
+                return Ok(());
             }
-            IntroductionResult::ExistingSession(token) => {
-                println!("Connecting client to existing session");
+        };
+
+        let connection = Connection::new(tcp_stream);
+        let sessions = sessions.clone();
+        let config = config.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        spawn(async move {
+            if let Err(e) = handle_connection(connection, sessions, config, tls_acceptor).await {
+                eprintln!("Couldn't introduce connection from {address}: {e}");
             }
-        }
+        });
     }
-
-    Ok(())
-}
\ No newline at end of file
+}
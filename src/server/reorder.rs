@@ -0,0 +1,190 @@
+use std::{collections::{BTreeMap, VecDeque}, ops::Range, time::{Duration, Instant}};
+
+use crate::connection::VideoChunk;
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ReorderStats {
+    /// Chunks that arrived ahead of a missing one and had to wait
+    pub reordered: u64,
+    /// Chunks that never arrived in time and were skipped over
+    pub lost: u64,
+    /// Chunks that arrived after their slot was already skipped
+    pub late: u64,
+    /// Extra copies of chunks that were already received, expected when
+    /// the client sends every chunk over all connections
+    pub duplicates: u64
+}
+
+/// How far back skipped chunks are remembered, to tell late chunks from duplicates
+const SKIPPED_HISTORY: u64 = 4096;
+
+///
+/// Jitter buffer that puts video chunks coming from several bonded
+/// connections back into sequence order. A chunk is held back until
+/// every chunk before it has been released, or until it has waited
+/// longer than `max_latency`, at which point the missing chunks are
+/// considered lost and skipped.
+/// 
+pub struct ReorderBuffer {
+    max_latency: Duration,
+    next_sequence: u64,
+    pending: BTreeMap<u64, (Instant, VideoChunk)>,
+    /// Sequence ranges that were given up on, oldest first
+    skipped: VecDeque<Range<u64>>,
+    pub stats: ReorderStats
+}
+
+impl ReorderBuffer {
+    pub fn new(max_latency: Duration) -> Self {
+        Self {
+            max_latency,
+            next_sequence: 0,
+            pending: BTreeMap::new(),
+            skipped: VecDeque::new(),
+            stats: ReorderStats::default()
+        }
+    }
+
+    /// Adds a chunk and returns the chunks that are now ready, in order
+    pub fn push(&mut self, chunk: VideoChunk) -> Vec<VideoChunk> {
+        if self.pending.contains_key(&chunk.sequence) {
+            self.stats.duplicates += 1;
+            return vec![];
+        }
+        if chunk.sequence < self.next_sequence {
+            if self.was_skipped(chunk.sequence) {
+                self.stats.late += 1;
+            } else {
+                self.stats.duplicates += 1;
+            }
+            return vec![];
+        }
+
+        if chunk.sequence > self.next_sequence {
+            self.stats.reordered += 1;
+        }
+        self.pending.insert(chunk.sequence, (Instant::now(), chunk));
+
+        self.release_ready()
+    }
+
+    /// Skips over gaps whose following chunk waited longer than `max_latency`
+    pub fn flush_expired(&mut self) -> Vec<VideoChunk> {
+        let mut released = vec![];
+        while let Some((&sequence, (received_at, _))) = self.pending.first_key_value() {
+            if received_at.elapsed() < self.max_latency {
+                break;
+            }
+
+            self.stats.lost += sequence - self.next_sequence;
+            self.skipped.push_back(self.next_sequence..sequence);
+            self.next_sequence = sequence;
+            self.forget_old_skips();
+            released.append(&mut self.release_ready());
+        }
+
+        released
+    }
+
+    fn was_skipped(&self, sequence: u64) -> bool {
+        // Chunks older than the history can only be very late
+        sequence < self.next_sequence.saturating_sub(SKIPPED_HISTORY)
+            || self.skipped.iter().any(|range| range.contains(&sequence))
+    }
+
+    fn forget_old_skips(&mut self) {
+        let oldest_remembered = self.next_sequence.saturating_sub(SKIPPED_HISTORY);
+        while self.skipped.front().is_some_and(|range| range.end <= oldest_remembered) {
+            self.skipped.pop_front();
+        }
+    }
+
+    fn release_ready(&mut self) -> Vec<VideoChunk> {
+        let mut released = vec![];
+        while let Some((_, chunk)) = self.pending.remove(&self.next_sequence) {
+            released.push(chunk);
+            self.next_sequence += 1;
+        }
+
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(sequence: u64) -> VideoChunk {
+        VideoChunk {
+            sequence,
+            timestamp: 0,
+            stream_start: false,
+            data: vec![sequence as u8]
+        }
+    }
+
+    fn sequences(released: Vec<VideoChunk>) -> Vec<u64> {
+        released.iter().map(|chunk| chunk.sequence).collect()
+    }
+
+    #[test]
+    fn releases_chunks_in_order() {
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(60));
+        for sequence in 0..3 {
+            assert_eq!(sequences(buffer.push(chunk(sequence))), [sequence]);
+        }
+        assert_eq!(buffer.stats.reordered, 0);
+    }
+
+    #[test]
+    fn holds_chunks_back_until_the_gap_is_filled() {
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(60));
+        assert_eq!(sequences(buffer.push(chunk(0))), [0]);
+        assert!(buffer.push(chunk(2)).is_empty());
+        assert!(buffer.push(chunk(3)).is_empty());
+        assert!(buffer.flush_expired().is_empty());
+
+        assert_eq!(sequences(buffer.push(chunk(1))), [1, 2, 3]);
+        assert_eq!(buffer.stats.reordered, 2);
+        assert_eq!(buffer.stats.lost, 0);
+    }
+
+    #[test]
+    fn skips_gaps_that_waited_too_long() {
+        let mut buffer = ReorderBuffer::new(Duration::ZERO);
+        assert_eq!(sequences(buffer.push(chunk(0))), [0]);
+        assert!(buffer.push(chunk(3)).is_empty());
+        assert!(buffer.push(chunk(5)).is_empty());
+
+        assert_eq!(sequences(buffer.flush_expired()), [3, 5]);
+        assert_eq!(buffer.stats.lost, 3);
+        assert_eq!(sequences(buffer.push(chunk(6))), [6]);
+    }
+
+    #[test]
+    fn drops_chunks_that_arrive_after_being_skipped() {
+        let mut buffer = ReorderBuffer::new(Duration::ZERO);
+        buffer.push(chunk(0));
+        buffer.push(chunk(2));
+        buffer.flush_expired();
+
+        assert!(buffer.push(chunk(1)).is_empty());
+        assert_eq!(buffer.stats.late, 1);
+        assert_eq!(buffer.stats.duplicates, 0);
+        assert_eq!(sequences(buffer.push(chunk(3))), [3]);
+    }
+
+    #[test]
+    fn counts_duplicates_separately_from_late_chunks() {
+        let mut buffer = ReorderBuffer::new(Duration::from_secs(60));
+        buffer.push(chunk(0));
+        // A copy of a released chunk and one of a chunk still waiting
+        assert!(buffer.push(chunk(0)).is_empty());
+        buffer.push(chunk(2));
+        assert!(buffer.push(chunk(2)).is_empty());
+
+        assert_eq!(buffer.stats.duplicates, 2);
+        assert_eq!(buffer.stats.late, 0);
+        assert_eq!(sequences(buffer.push(chunk(1))), [1, 2]);
+    }
+}
@@ -1,33 +1,250 @@
-use tokio::spawn;
+use std::time::{Duration, Instant};
+use tokio::{select, spawn, sync::oneshot, task::JoinHandle, time::{interval, timeout}};
 
-use crate::{connection::{Connection, ConnectionPacket, PacketType}, ffmpeg::FFmpeg, session::Session, ALLVU_VERSION};
+use crate::{auth::{new_challenge, verify_response}, connection::{Connection, ConnectionPacket, PacketType, RejectReason, VideoChunk}, flv::{FlvReader, FlvTag, TagKind}, ffmpeg::{log_progress, AudioEncoder, FFmpeg, FFmpegCommand, Input, InputType, Output, OutputType, VideoEncoder}, reorder::ReorderBuffer, session::{HeartbeatConfig, Session}, supervisor::{RestartPolicy, RestartReason, Supervisor, STOP_GRACE_PERIOD}, ALLVU_VERSION};
 use anyhow::anyhow;
+use tokio_rustls::TlsAcceptor;
+
+/// A write stuck for this long means FFmpeg stopped taking data, e.g.
+/// because the RTMP server stalled. Waiting longer would hold up the
+/// connection readers and with them the heartbeats.
+const FFMPEG_WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct ServerSession {
     session: Session,
-    ffmpeg: FFmpeg
+    /// Dropping the session drops this, which stops the packet processor
+    processor_shutdown: Option<oneshot::Sender<()>>,
+    processor: Option<JoinHandle<()>>
+}
+
+fn start_ffmpeg(rtmp_output: &str) -> anyhow::Result<FFmpeg> {
+    let command = FFmpegCommand::new()
+        .input(Input::new("pipe:0", InputType::FLV))
+        .video_encoder(VideoEncoder::Copy)
+        .audio_encoder(AudioEncoder::Copy)
+        .output(Output::new(rtmp_output, OutputType::FLV));
+    let mut ffmpeg = FFmpeg::new();
+    ffmpeg.start(command)?;
+
+    Ok(ffmpeg)
+}
+
+///
+/// Feeds the reassembled client stream into FFmpeg, which sends it to
+/// the RTMP server. FFmpeg is restarted if it fails or stops accepting
+/// data, and joins the stream at the next keyframe after getting the
+/// stream header again.
+/// 
+struct StreamForwarder {
+    rtmp_output: String,
+    ffmpeg: Option<FFmpeg>,
+    reader: FlvReader,
+    /// Frames are dropped until the next keyframe, a decoder can't start in the middle of a group
+    waiting_for_keyframe: bool,
+    /// Video codec of the stream FFmpeg is copying
+    ffmpeg_codec: Option<u32>,
+    supervisor: Supervisor,
+    /// FFmpeg isn't started again before this
+    next_start: Option<Instant>
+}
+
+impl StreamForwarder {
+    fn new(rtmp_output: String) -> Self {
+        Self {
+            rtmp_output,
+            ffmpeg: None,
+            reader: FlvReader::new(),
+            waiting_for_keyframe: true,
+            ffmpeg_codec: None,
+            supervisor: Supervisor::new("Session FFmpeg", RestartPolicy::default()),
+            next_start: None
+        }
+    }
+
+    async fn forward(&mut self, chunk: VideoChunk) {
+        if chunk.stream_start {
+            // The client restarted its encoder, a new FLV header follows
+            self.reader.start_stream();
+        }
+        let tags = self.reader.push(&chunk.data);
+        if self.reader.take_discontinuity() {
+            eprintln!("Stream data was lost, skipping to the next keyframe");
+            self.waiting_for_keyframe = true;
+        }
+
+        for tag in tags {
+            self.forward_tag(tag).await;
+        }
+    }
+
+    async fn forward_tag(&mut self, tag: FlvTag) {
+        // Metadata and sequence headers are part of the stream header
+        let is_header = tag.kind == TagKind::Script || tag.is_config();
+        if self.ffmpeg.is_none() {
+            if self.next_start.is_some_and(|next_start| Instant::now() < next_start) {
+                return;
+            }
+            if let Err(e) = self.restart().await {
+                self.failed(RestartReason::StartFailed(e.to_string())).await;
+                return;
+            }
+            // The reader already put this tag in the stream header
+            if is_header {
+                return;
+            }
+        }
+
+        if tag.kind == TagKind::Video && tag.is_config() {
+            let codec = tag.video_codec();
+            if self.ffmpeg_codec.is_some_and(|ffmpeg_codec| Some(ffmpeg_codec) != codec) {
+                // FFmpeg only copies the stream, it can't switch codecs in the middle of it
+                println!("Client switched the video codec, restarting FFmpeg");
+                self.stop_now().await;
+                if let Err(e) = self.restart().await {
+                    self.failed(RestartReason::StartFailed(e.to_string())).await;
+                }
+                return;
+            }
+            self.ffmpeg_codec = codec;
+        }
+
+        if self.waiting_for_keyframe {
+            let audio_only = tag.kind == TagKind::Audio && !self.reader.has_video();
+            if tag.is_keyframe() || audio_only {
+                self.waiting_for_keyframe = false;
+            } else if !is_header {
+                return;
+            }
+        }
+
+        let Some(ffmpeg) = &mut self.ffmpeg else {
+            return;
+        };
+        if let Some(error) = ffmpeg.fatal_error() {
+            self.failed(RestartReason::Failed(error)).await;
+            return;
+        }
+        match timeout(FFMPEG_WRITE_TIMEOUT, ffmpeg.write(tag.bytes)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                self.failed(RestartReason::Failed(format!("stopped accepting data: {e}"))).await;
+            }
+            Err(_) => {
+                let reason = format!("didn't take data for {}s", FFMPEG_WRITE_TIMEOUT.as_secs());
+                self.failed(RestartReason::Failed(reason)).await;
+            }
+        }
+    }
+
+    async fn failed(&mut self, reason: RestartReason) {
+        self.stop_now().await;
+        self.next_start = Some(Instant::now() + self.supervisor.restart_delay(&reason));
+    }
+
+    async fn stop_now(&mut self) {
+        if let Some(ffmpeg) = &mut self.ffmpeg {
+            let _ = ffmpeg.stop().await;
+        }
+        self.ffmpeg = None;
+    }
+
+    async fn restart(&mut self) -> anyhow::Result<()> {
+        let mut ffmpeg = start_ffmpeg(&self.rtmp_output)?;
+        // FFmpeg needs the file header and the codec configuration before the first keyframe
+        timeout(FFMPEG_WRITE_TIMEOUT, ffmpeg.write(self.reader.stream_header())).await
+            .map_err(|_| anyhow!("FFmpeg didn't take the stream header"))??;
+        log_progress(ffmpeg.progress(), PROGRESS_LOG_INTERVAL);
+        self.ffmpeg = Some(ffmpeg);
+        self.ffmpeg_codec = self.reader.video_codec();
+        self.waiting_for_keyframe = true;
+        self.supervisor.started();
+
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        if let Some(ffmpeg) = &mut self.ffmpeg {
+            let _ = ffmpeg.stop_gracefully(STOP_GRACE_PERIOD).await;
+        }
+        self.ffmpeg = None;
+    }
 }
 
 impl ServerSession {
-    pub fn new() -> Self {
-        let return_val = Self {
-            session: Session::new(),
-            ffmpeg: FFmpeg::new()
+    pub fn new(max_latency: Duration, heartbeat: HeartbeatConfig, rtmp_output: String) -> Self {
+        let mut session = Session::new();
+        session.set_heartbeat(heartbeat);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let mut return_val = Self {
+            session,
+            processor_shutdown: Some(shutdown_tx),
+            processor: None
         };
-        return_val.start_packet_processor();
+        return_val.start_packet_processor(max_latency, rtmp_output, shutdown_rx);
 
         return_val
     }
 
-    fn start_packet_processor(&self) {
+    fn start_packet_processor(&mut self, max_latency: Duration, rtmp_output: String, mut shutdown_rx: oneshot::Receiver<()>) {
         let packet_channel_arc = self.session.packet_channel.clone();
-        spawn(async move {
+        self.processor = Some(spawn(async move {
+            let mut forwarder = StreamForwarder::new(rtmp_output);
             let receiver = &mut packet_channel_arc.1.lock().await;
-            while let Some(packet) = receiver.recv().await {
-                println!("Received packet of size {}", packet.packet_data.len());
-                println!("Packet type {}", packet.packet_type);
+            let mut reorder_buffer = ReorderBuffer::new(max_latency);
+            // Gaps are only skipped on a tick, so tick a few times per latency window
+            let mut flush_interval = interval((max_latency / 4).max(Duration::from_millis(5)));
+            let mut stats_interval = interval(Duration::from_secs(10));
+            loop {
+                let released = select! {
+                    packet_option = receiver.recv() => {
+                        let Some(packet) = packet_option else {
+                            break;
+                        };
+                        if packet.packet_type != PacketType::VideoStream {
+                            println!("Packet type {:?}", packet.packet_type);
+                            continue;
+                        }
+                        match packet.to_video_chunk() {
+                            Ok(chunk) => {
+                                reorder_buffer.push(chunk)
+                            }
+                            Err(e) => {
+                                eprintln!("Invalid video packet: {e}");
+                                continue;
+                            }
+                        }
+                    }
+                    _ = flush_interval.tick() => {
+                        reorder_buffer.flush_expired()
+                    }
+                    _ = stats_interval.tick() => {
+                        let stats = reorder_buffer.stats;
+                        println!("Reordered {}, lost {}, late {}, duplicates {}", stats.reordered, stats.lost, stats.late, stats.duplicates);
+                        continue;
+                    }
+                    _ = &mut shutdown_rx => {
+                        break;
+                    }
+                };
+
+                for chunk in released {
+                    forwarder.forward(chunk).await;
+                }
             }
-        });
+
+            forwarder.stop().await;
+        }));
+    }
+
+    /// Stops the packet processor, letting FFmpeg finish the output
+    pub async fn stop(&mut self) {
+        if let Some(shutdown_tx) = self.processor_shutdown.take() {
+            let _ = shutdown_tx.send(());
+        }
+        if let Some(processor) = self.processor.take() {
+            let _ = processor.await;
+        }
     }
 
     pub fn add_connection(&mut self, connection: Connection)
@@ -39,14 +256,29 @@ impl ServerSession {
     {
         self.session.retreive_token()
     }
+
+    pub fn is_expired(&self, ttl: Duration) -> bool
+    {
+        self.session.is_expired(ttl)
+    }
 }
 
 pub enum IntroductionResult {
-    NewSession(String),
+    NewSession,
     ExistingSession(String)
 }
 
-pub async fn introduce_connection(connection: &mut Connection) -> anyhow::Result<IntroductionResult> {
+///
+/// Performs the handshake with a client. When the server has a TLS
+/// certificate, clients have to switch to TLS right after the greet
+/// exchange, otherwise they're rejected. Returns the (possibly
+/// upgraded) connection and what the client asked for.
+/// 
+pub async fn introduce_connection(
+    mut connection: Connection,
+    password: &str,
+    tls_acceptor: Option<&TlsAcceptor>
+) -> anyhow::Result<(Connection, IntroductionResult)> {
     let greet_packet = connection.read().await?;
     let client_greet = greet_packet.to_string()?;
     let greet_vec: Vec<&str> = client_greet.split("-").collect();
@@ -60,23 +292,65 @@ pub async fn introduce_connection(connection: &mut Connection) -> anyhow::Result
     let server_response_bytes = server_response.as_bytes().to_vec();
 
     let response_packet = ConnectionPacket {
-        packet_type: PacketType::InitialGreet as u8,
+        packet_type: PacketType::InitialGreet,
+        flags: 0,
         packet_data: server_response_bytes
     };
     connection.write(response_packet).await?;
 
-    let session_request_packet = connection.read().await?;
+    let mut session_request_packet = connection.read().await?;
+    if session_request_packet.packet_type == PacketType::StartTls {
+        let Some(tls_acceptor) = tls_acceptor else {
+            reject_connection(&mut connection, RejectReason::Encryption, "Encryption is not available on this server").await?;
+            return Err(anyhow!("Client asked for encryption, but no certificate is configured"));
+        };
+        connection.write(ConnectionPacket {
+            packet_type: PacketType::StartTls,
+            flags: 0,
+            packet_data: vec![]
+        }).await?;
+        connection = connection.start_tls_server(tls_acceptor).await?;
+        session_request_packet = connection.read().await?;
+    } else if tls_acceptor.is_some() {
+        reject_connection(&mut connection, RejectReason::Encryption, "Encryption is required by this server").await?;
+        return Err(anyhow!("Client tried to connect without encryption"));
+    }
+
     println!("Gotten session request packet");
-    println!("Packet type {}", session_request_packet.packet_type);
-    if session_request_packet.packet_type == PacketType::NewSession as u8 {
-        println!("New session");
-        let password = session_request_packet.to_string()?;
-        return Ok(IntroductionResult::NewSession(String::from(password)));
-    } else if session_request_packet.packet_type == PacketType::ExistingSession as u8 {
-        println!("Existing session");
-        let session_token = session_request_packet.to_string()?;
-        return Ok(IntroductionResult::ExistingSession(String::from(session_token)));
-    }
-    
-    Err(anyhow!("Invalid packet received"))
+    println!("Packet type {:?}", session_request_packet.packet_type);
+    let introduction_result = match session_request_packet.packet_type {
+        PacketType::NewSession => {
+            println!("New session");
+            IntroductionResult::NewSession
+        }
+        PacketType::ExistingSession => {
+            println!("Existing session");
+            let session_token = session_request_packet.to_string()?;
+            IntroductionResult::ExistingSession(String::from(session_token))
+        }
+        _ => {
+            return Err(anyhow!("Invalid packet received"));
+        }
+    };
+
+    // The client proves it knows the password without sending it
+    let challenge = new_challenge();
+    connection.write(ConnectionPacket {
+        packet_type: PacketType::AuthChallenge,
+        flags: 0,
+        packet_data: challenge.clone()
+    }).await?;
+
+    let auth_packet = connection.read().await?;
+    if auth_packet.packet_type != PacketType::AuthResponse
+        || !verify_response(password, &challenge, &auth_packet.packet_data) {
+        reject_connection(&mut connection, RejectReason::AuthFailed, "Authentication failed").await?;
+        return Err(anyhow!("Client failed to authenticate"));
+    }
+
+    Ok((connection, introduction_result))
+}
+
+pub async fn reject_connection(connection: &mut Connection, reason: RejectReason, message: &str) -> anyhow::Result<()> {
+    connection.write(ConnectionPacket::session_rejected(reason, message)).await
 }
\ No newline at end of file
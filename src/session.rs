@@ -1,19 +1,318 @@
-use std::sync::{atomic::AtomicU32, Arc};
+use std::{os::fd::RawFd, sync::{atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering}, Arc, RwLock}, time::{Duration, Instant}};
 use anyhow::anyhow;
-use rand::distr::{Alphanumeric, SampleString};
-use tokio::{spawn, sync::{mpsc::{self, Sender, Receiver}, Mutex}};
+use rand::{distr::{Alphanumeric, SampleString}, Rng};
+use serde::Deserialize;
+use tokio::{spawn, sync::{mpsc::{self, Sender, Receiver}, Mutex}, task::JoinSet, time::{interval, timeout}};
 
-use crate::connection::{Connection, ConnectionPacket, PacketType};
+use crate::connection::{queued_bytes, timestamp_micros, Connection, ConnectionPacket, ConnectionWriter, PacketType};
 
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
+/// Weight given to the newest throughput and latency samples when smoothing
+const THROUGHPUT_SMOOTHING: f64 = 0.2;
+/// Shortest time between two throughput samples, shorter ones are too noisy
+const THROUGHPUT_SAMPLE_INTERVAL: Duration = Duration::from_millis(200);
+
+// Connection health scoring
+/// Writes slower than this start adding penalty
+const SLOW_WRITE_THRESHOLD: Duration = Duration::from_millis(100);
+/// Round trip times above this start adding penalty
+const SLOW_RTT_THRESHOLD: Duration = Duration::from_millis(300);
+/// Penalty added for every failed write
+const ERROR_PENALTY: u32 = 200;
+/// Writers queued on the same connection before the backlog counts against it
+const BACKLOG_THRESHOLD: usize = 2;
+const MAX_PENALTY: u32 = 1000;
+/// Connections at or above this penalty are only used when every other one is as bad
+const AVOID_PENALTY: u32 = 300;
+/// Every interval the penalty goes down by a quarter
+const PENALTY_DECAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A write stuck for this long means the link stopped passing traffic
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+///
+/// Decides which of the session's connections a packet is sent over
+/// 
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulingStrategy {
+    /// Connections take turns
+    #[default]
+    RoundRobin,
+    /// Connections are picked in proportion to their measured throughput
+    WeightedThroughput,
+    /// The connection with the lowest round trip time is used first
+    LowestRtt,
+    /// Every packet is sent over all connections
+    Redundant
+}
+
+/// State of a connection's socket send queue at some point
+#[derive(Clone, Copy)]
+struct QueueSample {
+    at: Instant,
+    queued: usize,
+    bytes_sent: u64
+}
+
+#[derive(Clone, Copy)]
+pub struct ConnectionStats {
+    pub bytes_sent: u64,
+    /// Smoothed rate at which the link drains the socket send queue, in
+    /// bytes per second, 0 until measured
+    pub throughput: f64,
+    last_queue_sample: Option<QueueSample>,
+    /// Smoothed time a single write takes to be accepted by the socket
+    pub write_latency: Duration,
+    pub rtt: Option<Duration>,
+    pub errors: u32,
+    /// Health score of the link, higher is worse. Raised by slow writes,
+    /// slow round trips, errors and backlog, and decays over time.
+    pub penalty: u32,
+    last_decay: Instant
+}
+
+impl ConnectionStats {
+    fn new(penalty: u32) -> Self {
+        Self {
+            bytes_sent: 0,
+            throughput: 0.0,
+            last_queue_sample: None,
+            write_latency: Duration::ZERO,
+            rtt: None,
+            errors: 0,
+            penalty,
+            last_decay: Instant::now()
+        }
+    }
+
+    fn record_write(&mut self, bytes: usize, elapsed: Duration) {
+        self.bytes_sent += bytes as u64;
+
+        let latency = self.write_latency.as_secs_f64();
+        let smoothed_latency = latency + THROUGHPUT_SMOOTHING * (elapsed.as_secs_f64() - latency);
+        self.write_latency = Duration::from_secs_f64(smoothed_latency);
+        if elapsed > SLOW_WRITE_THRESHOLD {
+            // One point for every 10 ms over the threshold
+            self.add_penalty(((elapsed - SLOW_WRITE_THRESHOLD).as_millis() / 10) as u32);
+        }
+    }
+
+    ///
+    /// Measures the link from how fast its socket send queue drains.
+    /// A write returns as soon as the data is in the queue, so its
+    /// duration says little about the link itself. Packet headers and
+    /// TLS overhead aren't counted, they're small next to the video.
+    /// 
+    fn record_queue(&mut self, queued: usize) {
+        let now = Instant::now();
+        let Some(last) = self.last_queue_sample else {
+            self.last_queue_sample = Some(QueueSample { at: now, queued, bytes_sent: self.bytes_sent });
+            return;
+        };
+        let elapsed = now - last.at;
+        if elapsed < THROUGHPUT_SAMPLE_INTERVAL {
+            return;
+        }
+        self.last_queue_sample = Some(QueueSample { at: now, queued, bytes_sent: self.bytes_sent });
+
+        // Whatever was queued or written since the last sample and isn't queued anymore went out
+        let drained = (last.queued as u64 + self.bytes_sent - last.bytes_sent).saturating_sub(queued as u64);
+        let sample = drained as f64 / elapsed.as_secs_f64();
+        if queued == 0 {
+            // The link kept up with what it got, so it can carry at least this much
+            self.throughput = self.throughput.max(sample);
+        } else if self.throughput == 0.0 {
+            self.throughput = sample;
+        } else {
+            // Data is piling up, the link sends as fast as it can
+            self.throughput += THROUGHPUT_SMOOTHING * (sample - self.throughput);
+        }
+    }
+
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(rtt);
+        if rtt > SLOW_RTT_THRESHOLD {
+            self.add_penalty(((rtt - SLOW_RTT_THRESHOLD).as_millis() / 10) as u32);
+        }
+    }
+
+    fn record_error(&mut self) {
+        self.errors += 1;
+        self.add_penalty(ERROR_PENALTY);
+    }
+
+    fn record_backlog(&mut self, backlog: usize) {
+        if backlog > BACKLOG_THRESHOLD {
+            self.add_penalty((backlog - BACKLOG_THRESHOLD) as u32 * 5);
+        }
+    }
+
+    fn add_penalty(&mut self, penalty: u32) {
+        self.decay();
+        self.penalty = self.penalty.saturating_add(penalty).min(MAX_PENALTY);
+    }
+
+    fn decay(&mut self) {
+        while self.last_decay.elapsed() >= PENALTY_DECAY_INTERVAL && self.penalty > 0 {
+            self.penalty -= self.penalty.div_ceil(4);
+            self.last_decay += PENALTY_DECAY_INTERVAL;
+        }
+        if self.penalty == 0 {
+            self.last_decay = Instant::now();
+        }
+    }
+}
+
+///
+/// Totals over every connection of a session
+/// 
+#[derive(Default, Clone, Copy)]
+pub struct AggregateStats {
+    pub connections: usize,
+    pub bytes_sent: u64,
+    /// Bytes waiting in the connections' socket send queues
+    pub queued_bytes: usize,
+    /// Slowest smoothed write latency of all connections
+    pub max_write_latency: Duration
+}
+
+pub struct SessionConnection {
+    pub label: String,
+    writer: Mutex<ConnectionWriter>,
+    /// Writes waiting for or holding the writer lock
+    backlog: AtomicUsize,
+    socket_fd: Option<RawFd>,
+    /// Heartbeats sent since the last echo came back
+    missed_heartbeats: AtomicU32,
+    /// Set once a write timed out, the stream can't be trusted afterwards
+    closed: AtomicBool,
+    pub stats: Mutex<ConnectionStats>
+}
+
+impl SessionConnection {
+    async fn write(&self, packet: ConnectionPacket) -> anyhow::Result<()> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(anyhow!("Connection is closed"));
+        }
+
+        let packet_size = packet.packet_data.len();
+        let backlog = self.backlog.fetch_add(1, Ordering::AcqRel) + 1;
+        self.stats.lock().await.record_backlog(backlog);
+
+        let mut writer = self.writer.lock().await;
+        let started_at = Instant::now();
+        let write_result = match timeout(WRITE_TIMEOUT, writer.write(packet)).await {
+            Ok(write_result) => write_result,
+            Err(_) => {
+                // Part of the packet may have been written, so the link is unusable
+                self.closed.store(true, Ordering::Release);
+                Err(anyhow!("Write timed out"))
+            }
+        };
+        let elapsed = started_at.elapsed();
+        drop(writer);
+        self.backlog.fetch_sub(1, Ordering::AcqRel);
+
+        let mut stats = self.stats.lock().await;
+        match write_result {
+            Ok(()) => {
+                stats.record_write(packet_size, elapsed);
+                if let Some(queued) = self.socket_fd.and_then(queued_bytes) {
+                    stats.record_queue(queued);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                stats.record_error();
+                Err(e)
+            }
+        }
+    }
+
+    async fn current_stats(&self) -> ConnectionStats {
+        let mut stats = self.stats.lock().await;
+        stats.decay();
+        *stats
+    }
+
+    async fn handle_heartbeat(&self, packet: &ConnectionPacket) {
+        match packet.packet_type {
+            PacketType::Heartbeat => {
+                let echo = ConnectionPacket {
+                    packet_type: PacketType::HeartbeatAck,
+                    flags: 0,
+                    packet_data: packet.packet_data.clone()
+                };
+                if let Err(e) = self.write(echo).await {
+                    eprintln!("Couldn't answer heartbeat: {e}");
+                }
+            }
+            PacketType::HeartbeatAck => {
+                let Ok(sent_at_bytes) = packet.packet_data.as_slice().try_into() else {
+                    eprintln!("Invalid heartbeat echo");
+                    return;
+                };
+                let sent_at = u64::from_be_bytes(sent_at_bytes);
+                let rtt = Duration::from_micros(timestamp_micros().saturating_sub(sent_at));
+                self.missed_heartbeats.store(0, Ordering::Release);
+                self.stats.lock().await.record_rtt(rtt);
+            }
+            _ => {}
+        }
+    }
+}
+
+///
+/// Controls how often connections are probed and how many
+/// unanswered probes it takes to consider a connection dead
+/// 
+#[derive(Clone, Copy)]
+pub struct HeartbeatConfig {
+    pub interval: Duration,
+    pub max_missed: u32
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            max_missed: 5
+        }
+    }
+}
+
+///
+/// Builds the heartbeat settings from the config options. A zero
+/// interval or a zero missed heartbeat limit is refused, the first
+/// can't be timed and the second would drop every connection at once.
+/// 
+pub fn heartbeat_config(interval_ms: Option<u64>, max_missed: Option<u32>) -> anyhow::Result<HeartbeatConfig> {
+    let default = HeartbeatConfig::default();
+    if interval_ms == Some(0) {
+        return Err(anyhow!("heartbeat_interval has to be at least 1 ms"));
+    }
+    if max_missed == Some(0) {
+        return Err(anyhow!("max_missed_heartbeats has to be at least 1"));
+    }
+    Ok(HeartbeatConfig {
+        interval: interval_ms.map(Duration::from_millis).unwrap_or(default.interval),
+        max_missed: max_missed.unwrap_or(default.max_missed)
+    })
+}
+
 pub struct Session {
     id: u32,
     token: String,
-    connections: Vec<Arc<Mutex<Connection>>>,
+    connections: Arc<RwLock<Vec<Arc<SessionConnection>>>>,
+    /// Last time a connection was added or removed
+    last_change: Arc<RwLock<Instant>>,
     pub packet_channel: Arc<(Sender<ConnectionPacket>, Mutex<Receiver<ConnectionPacket>>)>,
+    heartbeat: HeartbeatConfig,
     // Load balancing fields
-    lb_index: u8
+    strategy: SchedulingStrategy,
+    lb_index: AtomicUsize
 }
 
 impl Session {
@@ -23,44 +322,114 @@ impl Session {
             let mpsc_channel = mpsc::channel(64);
             Arc::new((mpsc_channel.0, Mutex::new(mpsc_channel.1)))
         };
-        let to_return = Self {
-            id: NEXT_ID.fetch_add(1u32, std::sync::atomic::Ordering::AcqRel),
+        Self {
+            id: NEXT_ID.fetch_add(1u32, Ordering::AcqRel),
             token: session_token,
-            connections: vec![],
+            connections: Arc::new(RwLock::new(vec![])),
+            last_change: Arc::new(RwLock::new(Instant::now())),
             packet_channel,
-            lb_index: 0
-        };
+            heartbeat: HeartbeatConfig::default(),
+            strategy: SchedulingStrategy::default(),
+            lb_index: AtomicUsize::new(0)
+        }
+    }
 
-        to_return
+    pub fn set_strategy(&mut self, strategy: SchedulingStrategy) {
+        self.strategy = strategy;
     }
 
-    pub fn add_connection(&mut self, connection: Connection) {
-        let connection_arc = Arc::new(Mutex::new(connection));
-        self.connections.push(connection_arc.clone());
+    /// Only applies to connections added afterwards
+    pub fn set_heartbeat(&mut self, heartbeat: HeartbeatConfig) {
+        self.heartbeat = heartbeat;
+    }
+
+    pub fn add_connection(&self, connection: Connection) {
+        let initial_penalty = connection.penalty;
+        let label = connection.label.clone();
+        let socket_fd = connection.socket_fd();
+        let (mut reader, writer) = connection.into_split();
+        let session_connection = Arc::new(SessionConnection {
+            label,
+            writer: Mutex::new(writer),
+            backlog: AtomicUsize::new(0),
+            socket_fd,
+            missed_heartbeats: AtomicU32::new(0),
+            closed: AtomicBool::new(false),
+            stats: Mutex::new(ConnectionStats::new(initial_penalty))
+        });
+        self.connections.write().unwrap().push(session_connection.clone());
+        *self.last_change.write().unwrap() = Instant::now();
+
         let packet_channel_arc = self.packet_channel.clone();
+        let reader_connection = session_connection.clone();
+        let reader_handle = spawn(async move {
+            let ready_result = reader_connection.write(
+                ConnectionPacket { 
+                    packet_type: PacketType::ReadyForTransmission,
+                    flags: 0,
+                    packet_data: "AllVu Ready".as_bytes().to_vec()
+                }
+            ).await;
+            if let Err(e) = ready_result {
+                eprintln!("Couldn't send ready packet: {e}");
+                return;
+            }
+
+            loop {
+                let packet = match reader.read().await {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        // A broken frame means the stream can't be resynchronized
+                        eprintln!("Dropping connection {}: {e}", reader_connection.label);
+                        break;
+                    }
+                };
+                if matches!(packet.packet_type, PacketType::Heartbeat | PacketType::HeartbeatAck) {
+                    reader_connection.handle_heartbeat(&packet).await;
+                    continue;
+                }
+                let sender = &packet_channel_arc.0;
+                if sender.send(packet).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Probes the connection and retires it once it stops answering
+        let connections_arc = self.connections.clone();
+        let last_change_arc = self.last_change.clone();
+        let heartbeat = self.heartbeat;
         spawn(async move {
-            let mut sent_ready = false;
+            let mut heartbeat_interval = interval(heartbeat.interval);
             loop {
-                let mut lock = connection_arc.lock().await;
-                if !sent_ready {
-
-                    let Ok(_) = (*lock).write(
-                        ConnectionPacket { 
-                            packet_type: PacketType::ReadyForTransmission as u8, 
-                            packet_data: "AllVu Ready".as_bytes().to_vec()
-                        }
-                    ).await else {
-                        continue;
-                    };
-                    sent_ready = true;
+                heartbeat_interval.tick().await;
+                if reader_handle.is_finished() || session_connection.closed.load(Ordering::Acquire) {
+                    break;
                 }
-                let Ok(packet) = (*lock).read().await else {
-                    continue;
+
+                let missed = session_connection.missed_heartbeats.fetch_add(1, Ordering::AcqRel);
+                if missed >= heartbeat.max_missed {
+                    eprintln!("Connection {} missed {missed} heartbeats, dropping it", session_connection.label);
+                    break;
+                }
+
+                let probe = ConnectionPacket {
+                    packet_type: PacketType::Heartbeat,
+                    flags: 0,
+                    packet_data: timestamp_micros().to_be_bytes().to_vec()
                 };
-                drop(lock);
-                let sender = &packet_channel_arc.0;
-                sender.send(packet).await;
+                let probe_connection = session_connection.clone();
+                // A stalled write must not delay the missed heartbeat check
+                spawn(async move {
+                    let _ = probe_connection.write(probe).await;
+                });
             }
+
+            session_connection.closed.store(true, Ordering::Release);
+            reader_handle.abort();
+            connections_arc.write().unwrap()
+                .retain(|connection| !Arc::ptr_eq(connection, &session_connection));
+            *last_change_arc.write().unwrap() = Instant::now();
         });
     }
 
@@ -68,12 +437,244 @@ impl Session {
         self.token.clone()
     }
 
+    pub fn connection_count(&self) -> usize {
+        self.connections.read().unwrap().len()
+    }
+
+    pub async fn aggregate_stats(&self) -> AggregateStats {
+        let mut aggregate = AggregateStats::default();
+        for connection in self.active_connections() {
+            let stats = connection.current_stats().await;
+            aggregate.connections += 1;
+            aggregate.bytes_sent += stats.bytes_sent;
+            aggregate.queued_bytes += connection.socket_fd.and_then(queued_bytes).unwrap_or(0);
+            aggregate.max_write_latency = aggregate.max_write_latency.max(stats.write_latency);
+        }
+
+        aggregate
+    }
+
+    ///
+    /// Closes every connection with the given label. Their background
+    /// tasks notice on the next heartbeat and finish cleaning up.
+    /// 
+    pub fn remove_connection(&self, label: &str) {
+        let mut connections = self.connections.write().unwrap();
+        for connection in connections.iter().filter(|connection| connection.label == label) {
+            connection.closed.store(true, Ordering::Release);
+        }
+        connections.retain(|connection| connection.label != label);
+        *self.last_change.write().unwrap() = Instant::now();
+    }
+
+    pub fn connection_labels(&self) -> Vec<String> {
+        self.connections.read().unwrap().iter()
+            .map(|connection| connection.label.clone())
+            .collect()
+    }
+
+    /// A session expires once it has been left without connections for `ttl`
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        self.connection_count() == 0 && self.last_change.read().unwrap().elapsed() > ttl
+    }
+
+    fn active_connections(&self) -> Vec<Arc<SessionConnection>> {
+        self.connections.read().unwrap().iter()
+            .filter(|connection| !connection.closed.load(Ordering::Acquire))
+            .cloned()
+            .collect()
+    }
+
     pub async fn send(&self, packet: ConnectionPacket) -> anyhow::Result<()> {
-        if self.connections.len() == 0 {
+        let connections = self.active_connections();
+        if connections.is_empty() {
             return Err(anyhow!("There must be a connection inside of this session to send data"));
         }
-        let mut lock = self.connections[0].lock().await;
-        lock.write(packet).await?;
-        Ok(())
+
+        if self.strategy == SchedulingStrategy::Redundant {
+            return Self::send_redundant(&connections, packet).await;
+        }
+
+        // Fall back to the next candidate if the preferred connection fails
+        let mut last_error = None;
+        for index in self.schedule(&connections).await {
+            match connections[index].write(packet.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => last_error = Some(e)
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("No connection available")))
     }
-}
\ No newline at end of file
+
+    async fn send_redundant(connections: &[Arc<SessionConnection>], packet: ConnectionPacket) -> anyhow::Result<()> {
+        let mut writes = JoinSet::new();
+        for connection in connections {
+            let connection = connection.clone();
+            let packet = packet.clone();
+            writes.spawn(async move { connection.write(packet).await });
+        }
+
+        let mut last_error = None;
+        let mut any_sent = false;
+        while let Some(join_result) = writes.join_next().await {
+            match join_result {
+                Ok(Ok(())) => any_sent = true,
+                Ok(Err(e)) => last_error = Some(e),
+                Err(e) => last_error = Some(e.into())
+            }
+        }
+
+        if any_sent {
+            return Ok(());
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("No connection available")))
+    }
+
+    ///
+    /// Returns connection indexes in the order they should be tried
+    /// according to the session's scheduling strategy. Heavily
+    /// penalized connections are moved to the back, so they only get
+    /// used when the healthier ones fail.
+    /// 
+    async fn schedule(&self, connections: &[Arc<SessionConnection>]) -> Vec<usize> {
+        let mut stats: Vec<ConnectionStats> = Vec::with_capacity(connections.len());
+        for connection in connections {
+            stats.push(connection.current_stats().await);
+        }
+
+        let (healthy, penalized): (Vec<usize>, Vec<usize>) = (0..stats.len())
+            .partition(|index| stats[*index].penalty < AVOID_PENALTY);
+        if healthy.is_empty() {
+            return self.order_by_strategy(&stats, penalized);
+        }
+
+        let mut order = self.order_by_strategy(&stats, healthy);
+        let mut fallback = penalized;
+        fallback.sort_by_key(|index| stats[*index].penalty);
+        order.append(&mut fallback);
+        order
+    }
+
+    fn order_by_strategy(&self, stats: &[ConnectionStats], candidates: Vec<usize>) -> Vec<usize> {
+        let connection_count = candidates.len();
+        if connection_count == 0 {
+            return candidates;
+        }
+        let mut by_throughput: Vec<usize> = candidates.clone();
+        by_throughput.sort_by(|a, b| stats[*b].throughput.total_cmp(&stats[*a].throughput));
+
+        match self.strategy {
+            SchedulingStrategy::RoundRobin | SchedulingStrategy::Redundant => {
+                let start = self.lb_index.fetch_add(1, Ordering::Relaxed) % connection_count;
+                (0..connection_count).map(|offset| candidates[(start + offset) % connection_count]).collect()
+            }
+            SchedulingStrategy::WeightedThroughput => {
+                // Unmeasured connections get an average weight so they get probed
+                let measured: Vec<f64> = candidates.iter()
+                    .map(|index| stats[*index].throughput)
+                    .filter(|throughput| *throughput > 0.0)
+                    .collect();
+                let default_weight = if measured.is_empty() {
+                    1.0
+                } else {
+                    measured.iter().sum::<f64>() / measured.len() as f64
+                };
+                // A penalty of 100 halves the connection's share
+                let weights: Vec<f64> = candidates.iter()
+                    .map(|index| {
+                        let stat = &stats[*index];
+                        let throughput = if stat.throughput > 0.0 { stat.throughput } else { default_weight };
+                        throughput / (1.0 + stat.penalty as f64 / 100.0)
+                    })
+                    .collect();
+
+                let total_weight = weights.iter().sum::<f64>();
+                if total_weight <= 0.0 || !total_weight.is_finite() {
+                    return by_throughput;
+                }
+                let mut pick = rand::rng().random_range(0.0..total_weight);
+                let mut chosen = candidates[connection_count - 1];
+                for (index, weight) in candidates.iter().zip(weights.iter()) {
+                    if pick < *weight {
+                        chosen = *index;
+                        break;
+                    }
+                    pick -= weight;
+                }
+
+                let mut order = vec![chosen];
+                order.extend(by_throughput.into_iter().filter(|index| *index != chosen));
+                order
+            }
+            SchedulingStrategy::LowestRtt => {
+                // Connections without an RTT measurement go last, fastest first
+                let mut order = by_throughput;
+                order.sort_by_key(|index| stats[*index].rtt.unwrap_or(Duration::MAX));
+                order
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_config_refuses_zero() {
+        let default = heartbeat_config(None, None).unwrap();
+        assert_eq!(default.interval, Duration::from_secs(1));
+        assert_eq!(default.max_missed, 5);
+
+        let configured = heartbeat_config(Some(250), Some(3)).unwrap();
+        assert_eq!(configured.interval, Duration::from_millis(250));
+        assert_eq!(configured.max_missed, 3);
+
+        assert!(heartbeat_config(Some(0), None).is_err());
+        assert!(heartbeat_config(None, Some(0)).is_err());
+    }
+
+    /// Stats whose last send queue sample is `age` old
+    fn sampled_stats(age: Duration, queued: usize) -> ConnectionStats {
+        let mut stats = ConnectionStats::new(0);
+        stats.last_queue_sample = Some(QueueSample { at: Instant::now() - age, queued, bytes_sent: 0 });
+        stats
+    }
+
+    #[test]
+    fn throughput_follows_the_send_queue() {
+        // 100 kB written in a second, 50 kB of it still queued
+        let mut backlogged = sampled_stats(Duration::from_secs(1), 0);
+        backlogged.record_write(100_000, Duration::ZERO);
+        backlogged.record_queue(50_000);
+        assert!((49_000.0..=50_000.0).contains(&backlogged.throughput), "{}", backlogged.throughput);
+
+        // The queue went empty, so 100 kB/s is only a lower bound and can't lower the estimate
+        let mut keeping_up = sampled_stats(Duration::from_secs(1), 0);
+        keeping_up.throughput = 500_000.0;
+        keeping_up.record_write(100_000, Duration::ZERO);
+        keeping_up.record_queue(0);
+        assert_eq!(keeping_up.throughput, 500_000.0);
+
+        // Too soon after the last sample
+        let mut too_soon = sampled_stats(Duration::ZERO, 0);
+        too_soon.record_write(100_000, Duration::ZERO);
+        too_soon.record_queue(50_000);
+        assert_eq!(too_soon.throughput, 0.0);
+    }
+
+    #[test]
+    fn weighted_scheduling_handles_unusable_weights() {
+        let mut session = Session::new();
+        session.set_strategy(SchedulingStrategy::WeightedThroughput);
+        assert!(session.order_by_strategy(&[], vec![]).is_empty());
+
+        let mut stats = [ConnectionStats::new(0), ConnectionStats::new(0)];
+        stats[0].throughput = f64::MAX;
+        stats[1].throughput = f64::MAX;
+        let mut order = session.order_by_strategy(&stats, vec![0, 1]);
+        order.sort();
+        assert_eq!(order, [0, 1]);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{collections::VecDeque, fmt::Display, time::{Duration, Instant}};
+use tokio::{select, signal::unix::{signal, SignalKind}, spawn, sync::watch, time::sleep};
+
+/// How long a process gets to finish its output when stopped
+pub const STOP_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+///
+/// Limits on how often a process is restarted
+/// 
+pub struct RestartPolicy {
+    /// Delay before the first restart, doubled after every failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Restarts allowed within `restart_window`, further restarts
+    /// wait until the oldest one leaves the window
+    pub max_restarts: usize,
+    pub restart_window: Duration,
+    /// A process running this long is healthy again, the backoff starts over
+    pub healthy_after: Duration
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+            max_restarts: 5,
+            restart_window: Duration::from_secs(60),
+            healthy_after: Duration::from_secs(30)
+        }
+    }
+}
+
+pub enum RestartReason {
+    /// The process couldn't be started
+    StartFailed(String),
+    /// The process reported an error, stopped handling data or exited
+    Failed(String)
+}
+
+impl Display for RestartReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartReason::StartFailed(e) => write!(f, "couldn't start ({e})"),
+            RestartReason::Failed(e) => write!(f, "failed ({e})")
+        }
+    }
+}
+
+///
+/// Set once SIGINT or SIGTERM is received, shared by everything that
+/// has to stop gracefully
+/// 
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Starts listening for the signals, needs to be called from the runtime
+    pub fn listen() -> anyhow::Result<Self> {
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        spawn(async move {
+            select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+            println!("Shutting down...");
+            shutdown_tx.send_replace(true);
+            // Keep the sender, so receivers don't see the channel closing
+            shutdown_tx.closed().await;
+        });
+
+        Ok(Self(shutdown_rx))
+    }
+
+    pub fn requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once a shutdown is requested
+    pub async fn wait(&mut self) {
+        let _ = self.0.wait_for(|requested| *requested).await;
+    }
+}
+
+///
+/// Decides when a stopped process gets restarted, backing off
+/// exponentially and capping the restart rate
+/// 
+pub struct Supervisor {
+    name: &'static str,
+    policy: RestartPolicy,
+    backoff: Duration,
+    /// When the restarts within the window happened (or will happen)
+    restarts: VecDeque<Instant>,
+    started: Option<Instant>
+}
+
+impl Supervisor {
+    pub fn new(name: &'static str, policy: RestartPolicy) -> Self {
+        Self {
+            name,
+            backoff: policy.initial_backoff,
+            policy,
+            restarts: VecDeque::new(),
+            started: None
+        }
+    }
+
+    /// Called once the process is running
+    pub fn started(&mut self) {
+        self.started = Some(Instant::now());
+    }
+
+    ///
+    /// Reports why the process stopped and returns how long to wait
+    /// before starting it again
+    /// 
+    pub fn restart_delay(&mut self, reason: &RestartReason) -> Duration {
+        let now = Instant::now();
+        if self.started.take().is_some_and(|started| now - started >= self.policy.healthy_after) {
+            self.backoff = self.policy.initial_backoff;
+        }
+        let mut delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(self.policy.max_backoff);
+
+        while self.restarts.front().is_some_and(|restart| now.saturating_duration_since(*restart) >= self.policy.restart_window) {
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.policy.max_restarts {
+            if let Some(oldest) = self.restarts.front() {
+                delay = delay.max((*oldest + self.policy.restart_window).saturating_duration_since(now));
+            }
+        }
+        self.restarts.push_back(now + delay);
+
+        eprintln!("{} {reason}, restarting in {:.1}s", self.name, delay.as_secs_f32());
+        delay
+    }
+
+    ///
+    /// Waits out the restart delay. Returns false if a shutdown was
+    /// requested meanwhile, the process shouldn't be restarted then.
+    /// 
+    pub async fn wait_for_restart(&mut self, reason: RestartReason, shutdown: &mut Shutdown) -> bool {
+        let delay = self.restart_delay(&reason);
+        select! {
+            _ = sleep(delay) => !shutdown.requested(),
+            _ = shutdown.wait() => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_restarts: usize) -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(8),
+            max_restarts,
+            restart_window: Duration::from_secs(60),
+            healthy_after: Duration::from_secs(30)
+        }
+    }
+
+    fn failed() -> RestartReason {
+        RestartReason::Failed("test".into())
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut supervisor = Supervisor::new("Test", policy(100));
+        let delays: Vec<u64> = (0..6).map(|_| {
+            supervisor.started();
+            supervisor.restart_delay(&failed()).as_secs()
+        }).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 8, 8]);
+    }
+
+    #[test]
+    fn backoff_resets_after_a_healthy_run() {
+        let mut supervisor = Supervisor::new("Test", policy(100));
+        for _ in 0..3 {
+            supervisor.restart_delay(&failed());
+        }
+        assert_eq!(supervisor.restart_delay(&failed()), Duration::from_secs(8));
+
+        supervisor.started = Some(Instant::now() - Duration::from_secs(31));
+        assert_eq!(supervisor.restart_delay(&RestartReason::StartFailed("test".into())), Duration::from_secs(1));
+        // A run that didn't reach healthy_after keeps backing off
+        supervisor.started = Some(Instant::now() - Duration::from_secs(10));
+        assert_eq!(supervisor.restart_delay(&failed()), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn holds_off_once_the_window_budget_is_used() {
+        let mut supervisor = Supervisor::new("Test", RestartPolicy {
+            max_backoff: Duration::from_secs(1),
+            ..policy(3)
+        });
+        for _ in 0..3 {
+            assert_eq!(supervisor.restart_delay(&failed()), Duration::from_secs(1));
+        }
+        // The next restart waits until the first one leaves the window
+        let delay = supervisor.restart_delay(&failed());
+        assert!(delay > Duration::from_secs(60) && delay <= Duration::from_secs(61), "{delay:?}");
+    }
+}
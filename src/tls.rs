@@ -0,0 +1,36 @@
+use std::sync::Arc;
+use tokio_rustls::{rustls::{pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer}, ClientConfig, RootCertStore, ServerConfig}, TlsAcceptor, TlsConnector};
+
+///
+/// Builds the server side of the TLS channel from a PEM certificate
+/// chain and the matching PEM private key.
+/// 
+pub fn load_acceptor(certificate_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_file_iter(certificate_path)?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+///
+/// Builds the client side of the TLS channel. Only certificates issued
+/// by the CAs in the given PEM file are trusted, which pins the client
+/// to servers signed with the operator's own CA.
+/// 
+pub fn load_connector(ca_path: &str) -> anyhow::Result<TlsConnector> {
+    let mut root_store = RootCertStore::empty();
+    for certificate in CertificateDer::pem_file_iter(ca_path)? {
+        root_store.add(certificate?)?;
+    }
+
+    let config = ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}